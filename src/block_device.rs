@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+// Storage a FAT32 volume lives on. Offsets are in bytes from the start of
+// the volume; reads and writes either transfer the whole buffer or fail.
+pub trait BlockDevice {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    // Size of the device in bytes
+    fn size(&mut self) -> io::Result<u64>;
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.read_at(sector * buffer.len() as u64, buffer)
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8]) -> io::Result<()> {
+        self.write_at(sector * buffer.len() as u64, buffer)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buffer)
+    }
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        (**self).write_at(offset, buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+// Helpers shared by the devices backed by a seekable stream
+fn stream_read_at<S: Read + Seek>(stream: &mut S, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    stream.seek(SeekFrom::Start(offset))?;
+    stream.read_exact(buffer)
+}
+
+fn stream_write_at<S: Write + Seek>(stream: &mut S, offset: u64, buffer: &[u8]) -> io::Result<()> {
+    stream.seek(SeekFrom::Start(offset))?;
+    stream.write_all(buffer)
+}
+
+impl BlockDevice for File {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        stream_read_at(self, offset, buffer)
+    }
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        stream_write_at(self, offset, buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl BlockDevice for Cursor<Vec<u8>> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        stream_read_at(self, offset, buffer)
+    }
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        stream_write_at(self, offset, buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
+    }
+}
+
+// A plain buffer is a fixed-size device: accesses past its end fail
+impl BlockDevice for Vec<u8> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let range = buffer_range(self.len(), offset, buffer.len())?;
+        buffer.copy_from_slice(&self[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        let range = buffer_range(self.len(), offset, buffer.len())?;
+        self[range].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+fn buffer_range(size: usize, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+    let start = offset as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= size => Ok(start..end),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Access past the end of the device.",
+        )),
+    }
+}
+
+// A window of `length` bytes starting at `start` on another device, such as
+// a partition inside a whole-disk image
+pub struct OffsetDevice<D: BlockDevice> {
+    inner: D,
+    start: u64,
+    length: u64,
+}
+
+impl<D: BlockDevice> OffsetDevice<D> {
+    pub fn new(mut inner: D, start: u64, length: Option<u64>) -> io::Result<Self> {
+        let inner_size = inner.size()?;
+        if start > inner_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Offset is past the end of the device.",
+            ));
+        }
+        // Default to the rest of the device
+        let length = length.unwrap_or(inner_size - start);
+        if length > inner_size - start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Length runs past the end of the device.",
+            ));
+        }
+        Ok(OffsetDevice {
+            inner,
+            start,
+            length,
+        })
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.length => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Access past the end of the device.",
+            )),
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for OffsetDevice<D> {
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buffer.len())?;
+        self.inner.read_at(self.start + offset, buffer)
+    }
+
+    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> io::Result<()> {
+        self.check_range(offset, buffer.len())?;
+        self.inner.write_at(self.start + offset, buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::Fs;
+    use crate::mkfs::{self, MkfsOptions};
+
+    const IMAGE_SIZE: usize = 40 * 1024 * 1024;
+
+    // Formats `device`, writes a file, mounts it again and reads the file back
+    fn round_trip<D: BlockDevice>(mut device: D) {
+        mkfs::format(&mut device, &MkfsOptions::default()).unwrap();
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        {
+            let mut fs = Fs::new(&mut device).unwrap();
+            fs.create_dir("docs").unwrap();
+            let mut file = fs.create("docs/data.bin").unwrap();
+            file.write_all(&data).unwrap();
+            file.flush().unwrap();
+        }
+
+        let mut fs = Fs::new(&mut device).unwrap();
+        let mut read_back = Vec::new();
+        fs.open("docs/data.bin").unwrap().read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, data);
+    }
+
+    #[test]
+    fn vec_round_trip() {
+        round_trip(vec![0u8; IMAGE_SIZE]);
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        round_trip(Cursor::new(vec![0u8; IMAGE_SIZE]));
+    }
+
+    #[test]
+    fn offset_device_round_trip() {
+        // A volume starting 1 MiB into a disk, with data on either side
+        let mut disk = vec![0xAAu8; IMAGE_SIZE + 2 * 1024 * 1024];
+        let partition = OffsetDevice::new(&mut disk, 1024 * 1024, Some(IMAGE_SIZE as u64)).unwrap();
+        round_trip(partition);
+        assert!(disk[..1024 * 1024].iter().all(|&b| b == 0xAA));
+        assert!(disk[1024 * 1024 + IMAGE_SIZE..].iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn vec_accesses_past_the_end_fail() {
+        let mut device = vec![0u8; 1024];
+        device.write_at(1020, &[1, 2, 3, 4]).unwrap();
+        assert!(device.write_at(1021, &[1, 2, 3, 4]).is_err());
+        assert!(device.read_at(u64::MAX, &mut [0u8; 1]).is_err());
+        assert_eq!(device.len(), 1024);
+    }
+
+    #[test]
+    fn offset_device_bounds() {
+        let mut inner = vec![0u8; 4096];
+        assert!(OffsetDevice::new(&mut inner, 4097, None).is_err());
+        assert!(OffsetDevice::new(&mut inner, 3072, Some(2048)).is_err());
+        assert_eq!(OffsetDevice::new(&mut inner, 4096, None).unwrap().size().unwrap(), 0);
+
+        let mut device = OffsetDevice::new(&mut inner, 1024, Some(1024)).unwrap();
+        assert_eq!(device.size().unwrap(), 1024);
+        device.write_at(0, &[1]).unwrap();
+        device.write_at(1023, &[2]).unwrap();
+        assert!(device.write_at(1023, &[3, 3]).is_err());
+        assert!(device.read_at(1024, &mut [0u8; 1]).is_err());
+        assert!(device.read_at(u64::MAX, &mut [0u8; 1]).is_err());
+        assert_eq!((inner[1024], inner[2047], inner[2048]), (1, 2, 0));
+    }
+}
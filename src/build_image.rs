@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{FsError, Result};
+use crate::fs::Fs;
+use crate::host_copy::{self, host_error};
+use crate::mkfs::{self, MkfsOptions};
+use crate::timestamp::Timestamp;
+
+// Images are never made smaller than this unless a size is given
+const DEFAULT_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+// What `build_image` copied
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub directories: usize,
+    pub files: usize,
+    pub bytes: u64,
+    // Image paths of entries whose host name didn't fit in 8.3 form,
+    // with the short name they were given
+    pub shortened: Vec<(String, String)>,
+    // Host paths that were left out because they are neither files nor
+    // directories, e.g. symlinks
+    pub skipped: Vec<PathBuf>,
+}
+
+// Creates a FAT32 image at `out` holding a copy of the host directory tree
+// under `src`. When `size` is None the image is sized to fit the tree.
+// Modification times are copied into the directory entries.
+pub fn build_image(
+    src: &Path,
+    out: &Path,
+    size: Option<u64>,
+    options: &MkfsOptions,
+) -> Result<BuildReport> {
+    if !std::fs::metadata(src).map_err(|e| host_error(src, e))?.is_dir() {
+        return Err(FsError::NotADirectory(src.display().to_string()));
+    }
+
+    let size = match size {
+        Some(size) => size,
+        None => estimate_size(src, options)?,
+    };
+    mkfs::create_image(out, size, options)?;
+
+    // Don't leave a half-filled image behind
+    let result = fill_image(src, out);
+    if result.is_err() {
+        let _ = std::fs::remove_file(out);
+    }
+    result
+}
+
+// Copies the tree under `src` into the freshly made image at `out`
+fn fill_image(src: &Path, out: &Path) -> Result<BuildReport> {
+    let mut fs = Fs::open_path(out)?;
+    let mut report = BuildReport::default();
+    let mut skipped = Vec::new();
+    host_copy::copy_children(&mut fs, src, "", &mut skipped, &mut |fs, copied| {
+        let entry = copied.entry;
+        if entry.long_name.is_some() && entry.name != entry.display_name().to_ascii_uppercase() {
+            report.shortened.push((copied.image_path.to_string(), entry.name.clone()));
+        }
+        match copied.bytes {
+            Some(bytes) => {
+                report.files += 1;
+                report.bytes += bytes;
+            }
+            None => report.directories += 1,
+        }
+        // Copying a file's contents sets its times to now, so they are set
+        // from the host afterwards
+        if let Ok(modified) = copied.metadata.modified() {
+            let time = Timestamp::from_system_time(modified);
+            fs.fat32_mut()
+                .set_entry_times(entry.offset, Some(time), Some(time), Some(time))?;
+        }
+        Ok(())
+    })?;
+    report.skipped = skipped;
+    fs.flush()?;
+    Ok(report)
+}
+
+// Bytes needed to hold the tree under `src`, plus room for the FATs and
+// some slack. Larger images get larger clusters, which waste more space on
+// small files, so the estimate is redone until it assumes the cluster size
+// mkfs picks for the result.
+fn estimate_size(src: &Path, options: &MkfsOptions) -> Result<u64> {
+    let mut cluster = mkfs::cluster_size(DEFAULT_MIN_SIZE, options)? as u64;
+    loop {
+        let data = tree_size(src, cluster)?;
+        // The two FATs take 8 bytes per cluster, at most 1/64 of the data;
+        // the rest is slack
+        let size = data + data / 8 + 8 * 1024 * 1024;
+        let size = std::cmp::max(DEFAULT_MIN_SIZE, size.div_ceil(1024 * 1024) * 1024 * 1024);
+        let picked = mkfs::cluster_size(size, options)? as u64;
+        if picked <= cluster {
+            return Ok(size);
+        }
+        cluster = picked;
+    }
+}
+
+// Bytes the directory `dir` and everything below it take up with clusters
+// of `cluster` bytes. Only what `copy_children` copies is counted.
+fn tree_size(dir: &Path, cluster: u64) -> Result<u64> {
+    // The directory's own entries: '.', '..' and one short and up to 20
+    // long name entries per child
+    let mut dir_bytes = 64;
+    let mut total = 0;
+    for child in std::fs::read_dir(dir).map_err(|e| host_error(dir, e))? {
+        let child = child.map_err(|e| host_error(dir, e))?;
+        let path = child.path();
+        let metadata = std::fs::symlink_metadata(&path).map_err(|e| host_error(&path, e))?;
+        if metadata.is_dir() {
+            total += tree_size(&path, cluster)?;
+        } else if metadata.is_file() {
+            total += metadata.len().div_ceil(cluster) * cluster;
+        } else {
+            continue;
+        }
+        dir_bytes += 32 * (1 + child.file_name().len().div_ceil(13) as u64);
+    }
+    Ok(total + dir_bytes.div_ceil(cluster) * cluster)
+}
@@ -1,190 +1,458 @@
-use std::fs::File;
-use std::io::{self};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use filesys::block_device::BlockDevice;
+use filesys::error::{FsError, Result};
+use filesys::fs::Fs;
+use filesys::fsck;
+use filesys::host_copy::{self, host_error, join_image_path};
+use filesys::timestamp::Timestamp;
 
-use crate::fat32::{DirectoryEntry, FAT32};
 use crate::open_files::{FileMode, OpenFile, OpenFiles};
 
-pub fn info(fat32: &FAT32) -> io::Result<()> {
-    println!(
-        "Position of root cluster (cluster #): {}",
-        fat32.boot_sector.root_cluster
-    );
-    println!("Bytes per sector: {}", fat32.boot_sector.bytes_per_sector);
-    println!(
-        "Sectors per cluster: {}",
-        fat32.boot_sector.sectors_per_cluster
-    );
-    println!(
-        "Total # of clusters in data region: {}",
-        fat32.total_clusters
-    );
-    let num_fat_entries = (fat32.boot_sector.fat_size_32
-        * fat32.boot_sector.bytes_per_sector as u32)
-        / 4;
-    println!("# of entries in one FAT: {}", num_fat_entries);
-    let size_of_image = fat32.boot_sector.total_sectors as u64
-        * fat32.boot_sector.bytes_per_sector as u64;
-    println!("Size of image (in bytes): {}", size_of_image);
+// A value shown by `info`
+enum InfoValue {
+    Number(u64),
+    // A number that reads better in hex, e.g. the media descriptor
+    Hex(u64),
+    Flag(bool),
+    Text(String),
+    // Not available; the text is shown instead, or null in JSON
+    Missing(&'static str),
+}
+
+impl InfoValue {
+    fn to_json(&self) -> String {
+        match self {
+            InfoValue::Number(value) | InfoValue::Hex(value) => value.to_string(),
+            InfoValue::Flag(value) => value.to_string(),
+            InfoValue::Text(text) => json_string(text),
+            InfoValue::Missing(_) => "null".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for InfoValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InfoValue::Number(value) => write!(f, "{}", value),
+            InfoValue::Hex(value) => write!(f, "{:#04X}", value),
+            InfoValue::Flag(true) => write!(f, "yes"),
+            InfoValue::Flag(false) => write!(f, "no"),
+            InfoValue::Text(text) => write!(f, "{}", text),
+            InfoValue::Missing(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+// Prints every boot sector and FSInfo field along with the layout and free
+// space, then warns about anything unusual. With `json` the same is printed
+// as one JSON object.
+pub fn info<D: BlockDevice>(fs: &mut Fs<D>, json: bool) -> Result<()> {
+    let fat32 = fs.fat32_mut();
+    let warnings = fat32.anomalies()?;
+    let label = fat32.volume_label()?;
+    let fs_info = fat32.read_fs_info()?;
+    let fat32 = fs.fat32();
+    let bs = &fat32.boot_sector;
+
+    use InfoValue::*;
+    let fs_info_value = |value: Option<u32>| match value {
+        Some(0xFFFFFFFF) => Missing("unknown"),
+        Some(value) => Number(value as u64),
+        None => Missing("no FSInfo sector"),
+    };
+    let fields = [
+        ("volume_label", "Volume label", label.map_or(Missing("(none)"), Text)),
+        (
+            "volume_serial",
+            "Volume serial number",
+            Text(format!("{:04X}-{:04X}", bs.volume_id >> 16, bs.volume_id & 0xFFFF)),
+        ),
+        ("oem_name", "OEM name", Text(bs.oem_name.clone())),
+        ("fs_type", "File system type", Text(bs.fs_type.clone())),
+        (
+            "jump",
+            "Jump instruction",
+            Text(format!("{:02X} {:02X} {:02X}", bs.jump[0], bs.jump[1], bs.jump[2])),
+        ),
+        ("bytes_per_sector", "Bytes per sector", Number(bs.bytes_per_sector as u64)),
+        ("sectors_per_cluster", "Sectors per cluster", Number(bs.sectors_per_cluster as u64)),
+        ("bytes_per_cluster", "Bytes per cluster", Number(fat32.bytes_per_cluster() as u64)),
+        ("reserved_sectors", "Reserved sectors", Number(bs.reserved_sector_count as u64)),
+        ("num_fats", "Number of FATs", Number(bs.num_fats as u64)),
+        ("root_entry_count", "Root entry count (FAT12/16)", Number(bs.root_entry_count as u64)),
+        ("total_sectors_16", "Total sectors (16-bit)", Number(bs.total_sectors_16 as u64)),
+        ("media", "Media descriptor", Hex(bs.media as u64)),
+        ("fat_size_16", "Sectors per FAT (16-bit)", Number(bs.fat_size_16 as u64)),
+        ("sectors_per_track", "Sectors per track", Number(bs.sectors_per_track as u64)),
+        ("num_heads", "Number of heads", Number(bs.num_heads as u64)),
+        ("hidden_sectors", "Hidden sectors", Number(bs.hidden_sectors as u64)),
+        ("total_sectors_32", "Total sectors (32-bit)", Number(bs.total_sectors_32 as u64)),
+        ("fat_size_32", "Sectors per FAT (32-bit)", Number(bs.fat_size_32 as u64)),
+        ("ext_flags", "Extended flags", Hex(bs.ext_flags as u64)),
+        ("fat_mirroring", "FAT mirroring", Flag(fat32.fat_mirroring())),
+        ("active_fat", "Active FAT", Number(fat32.active_fat() as u64)),
+        ("fs_version", "File system version", Hex(bs.fs_version as u64)),
+        ("root_cluster", "Position of root cluster (cluster #)", Number(bs.root_cluster as u64)),
+        ("fs_info_sector", "FSInfo sector", Number(bs.fs_info_sector as u64)),
+        ("backup_boot_sector", "Backup boot sector", Number(bs.backup_boot_sector as u64)),
+        ("drive_number", "Drive number", Hex(bs.drive_number as u64)),
+        ("boot_signature", "Extended boot signature", Hex(bs.boot_signature as u64)),
+        ("boot_sector_label", "Volume label (boot sector)", Text(bs.volume_label.clone())),
+        ("signature", "Boot sector signature", Hex(bs.signature as u64)),
+        (
+            "fs_info_free_count",
+            "FSInfo free cluster count",
+            fs_info_value(fs_info.as_ref().map(|fs_info| fs_info.free_count)),
+        ),
+        (
+            "fs_info_next_free",
+            "Next free cluster hint",
+            fs_info_value(fs_info.as_ref().map(|fs_info| fs_info.next_free)),
+        ),
+        ("fat_offset", "FAT offset (in bytes)", Number(fat32.fat_offset)),
+        ("data_region_offset", "Data region offset (in bytes)", Number(fat32.data_region_offset)),
+        ("total_clusters", "Total # of clusters in data region", Number(fat32.total_clusters as u64)),
+        (
+            "fat_entries",
+            "# of entries in one FAT",
+            Number(bs.fat_size_32 as u64 * bs.bytes_per_sector as u64 / 4),
+        ),
+        (
+            "image_size",
+            "Size of image (in bytes)",
+            Number(bs.total_sectors as u64 * bs.bytes_per_sector as u64),
+        ),
+        ("free_clusters", "Free clusters", Number(fat32.free_clusters() as u64)),
+        (
+            "free_space",
+            "Free space (in bytes)",
+            Number(fat32.free_clusters() as u64 * fat32.bytes_per_cluster() as u64),
+        ),
+    ];
+
+    if json {
+        println!("{{");
+        for (key, _, value) in &fields {
+            println!("  {}: {},", json_string(key), value.to_json());
+        }
+        let warnings: Vec<String> = warnings.iter().map(|warning| json_string(warning)).collect();
+        println!("  \"warnings\": [{}]", warnings.join(", "));
+        println!("}}");
+    } else {
+        for (_, label, value) in &fields {
+            println!("{}: {}", label, value);
+        }
+        for warning in &warnings {
+            println!("Warning: {}", warning);
+        }
+    }
     Ok(())
 }
 
-pub fn ls(
-    image_file: &mut File,
-    fat32: &FAT32,
-    current_dir_cluster: u32,
-) -> io::Result<()> {
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
+// `text` as a quoted JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
 
-    for entry in entries {
-        println!("{}", format_name(&entry.name));
+pub fn fatcheck<D: BlockDevice>(fs: &mut Fs<D>, repair: bool) -> Result<()> {
+    let fat32 = fs.fat32_mut();
+    let divergent = fat32.find_divergent_fats()?;
+    if divergent.is_empty() {
+        println!("All {} FAT copies are consistent.", fat32.boot_sector.num_fats);
+        return Ok(());
     }
 
+    for index in &divergent {
+        println!("FAT #{} differs from active FAT #{}.", index, fat32.active_fat());
+    }
+    if repair {
+        fat32.sync_fat_copies()?;
+        println!("FAT copies resynchronized from FAT #{}.", fat32.active_fat());
+    }
     Ok(())
 }
 
-pub fn cd(
-    image_file: &mut File,
-    fat32: &FAT32,
-    current_dir_cluster: &mut u32,
-    dirname: &str,
-) -> io::Result<()> {
-    if dirname == "." {
-        // Do nothing
+// Checks the image. With `dry_run` the repairs are only listed; with
+// `repair` they are made and the image is checked again.
+pub fn fsck<D: BlockDevice>(
+    fs: &mut Fs<D>,
+    repair: bool,
+    dry_run: bool,
+    salvage: bool,
+) -> Result<()> {
+    let fat32 = fs.fat32_mut();
+    let report = fsck::check(fat32)?;
+    println!(
+        "Checked {} directories and {} files using {} clusters.",
+        report.directories, report.files, report.used_clusters
+    );
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.is_clean() {
+        println!("No problems found.");
         return Ok(());
-    } else if dirname == ".." {
-        // Navigate to parent directory
-        let entries = fat32.read_directory_entries(image_file, *current_dir_cluster)?;
-        for entry in entries {
-            if format_name(&entry.name) == ".." {
-                *current_dir_cluster = entry.first_cluster;
-                return Ok(());
-            }
+    }
+    if !repair && !dry_run {
+        return Err(problems_found(report.problems.len()));
+    }
+
+    let fixes = fsck::plan_repairs(fat32, &report, salvage)?;
+    if dry_run {
+        println!("Repairs that would be made:");
+        for fix in &fixes {
+            println!("  {}", fix);
         }
-        eprintln!("Error: Parent directory not found.");
-        return Ok(());
+        return Err(problems_found(report.problems.len()));
+    }
+
+    fsck::apply_repairs(fat32, &fixes)?;
+    println!("Repairs made:");
+    for fix in &fixes {
+        println!("  {}", fix);
+    }
+
+    let report = fsck::check(fat32)?;
+    if report.is_clean() {
+        println!("All problems repaired.");
+        Ok(())
     } else {
-        // Find the directory with name DIRNAME
-        let entries = fat32.read_directory_entries(image_file, *current_dir_cluster)?;
+        for problem in &report.problems {
+            println!("Remaining: {}", problem);
+        }
+        Err(problems_found(report.problems.len()))
+    }
+}
+
+fn problems_found(count: usize) -> FsError {
+    FsError::corrupted(format!("{} problem(s) found.", count))
+}
+
+pub fn pwd<D: BlockDevice>(fs: &Fs<D>) -> Result<()> {
+    println!("{}", fs.current_path());
+    Ok(())
+}
+
+// How `ls` lists a directory
+#[derive(Default)]
+pub struct LsOptions {
+    // One line per entry with attributes, size, clusters and times
+    pub long: bool,
+    // Include hidden and system entries
+    pub all: bool,
+    pub sort: LsSort,
+    pub reverse: bool,
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum LsSort {
+    // Order of the entries in the directory
+    #[default]
+    Directory,
+    // Largest first
+    Size,
+    // Most recently modified first
+    Time,
+}
+
+pub fn ls<D: BlockDevice>(fs: &mut Fs<D>, dirname: &str, options: &LsOptions) -> Result<()> {
+    let mut entries = fs.read_dir(dirname)?;
+
+    if !options.all {
+        entries.retain(|entry| !entry.is_hidden());
+    }
+    match options.sort {
+        LsSort::Directory => {}
+        LsSort::Size => entries.sort_by_key(|entry| std::cmp::Reverse(entry.file_size)),
+        LsSort::Time => entries.sort_by_key(|entry| std::cmp::Reverse(entry.modified)),
+    }
+    if options.reverse {
+        entries.reverse();
+    }
+
+    if !options.long {
         for entry in entries {
-            if format_name(&entry.name) == dirname && entry.is_directory() {
-                *current_dir_cluster = entry.first_cluster;
-                return Ok(());
-            }
+            println!("{}", entry.display_name());
         }
-        eprintln!("Error: Directory '{}' not found.", dirname);
         return Ok(());
     }
-}
 
-pub fn mkdir(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
-    dirname: &str,
-) -> io::Result<()> {
-    // Check if directory already exists
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
+    println!(
+        "{:<6}  {:>10}  {:>8}  {:>8}  {:<19}  {:<19}  {:<10}  Name",
+        "Attr", "Size", "Cluster", "Clusters", "Created", "Modified", "Accessed"
+    );
     for entry in entries {
-        if format_name(&entry.name) == dirname {
-            eprintln!("Error: Directory '{}' already exists.", dirname);
-            return Ok(());
-        }
+        let time = |time: Option<Timestamp>| match time {
+            Some(time) => time.to_string(),
+            None => "-".to_string(),
+        };
+        let accessed = match entry.accessed {
+            Some(accessed) => format!(
+                "{:04}-{:02}-{:02}",
+                accessed.year, accessed.month, accessed.day
+            ),
+            None => "-".to_string(),
+        };
+        // A broken chain shouldn't stop the listing; fsck reports it
+        let clusters = match fs.fat32().get_cluster_chain(entry.first_cluster) {
+            Ok(chain) => chain.len().to_string(),
+            Err(_) => "?".to_string(),
+        };
+        println!(
+            "{:<6}  {:>10}  {:>8}  {:>8}  {:<19}  {:<19}  {:<10}  {}",
+            entry.attr_flags(),
+            entry.file_size,
+            entry.first_cluster,
+            clusters,
+            time(entry.created),
+            time(entry.modified),
+            accessed,
+            entry.display_name()
+        );
     }
 
-    // Create the directory
-    fat32.create_directory(image_file, current_dir_cluster, dirname)?;
+    Ok(())
+}
 
-    println!("Directory '{}' created.", dirname);
+// Shows the volume label, or sets it to `new_label`
+pub fn label<D: BlockDevice>(fs: &mut Fs<D>, new_label: Option<&str>) -> Result<()> {
+    match new_label {
+        Some(new_label) => {
+            fs.fat32_mut().set_volume_label(new_label)?;
+            println!("Volume label set to '{}'.", new_label.to_ascii_uppercase());
+        }
+        None => match fs.fat32_mut().volume_label()? {
+            Some(label) => println!("Volume label: {}", label),
+            None => println!("The volume has no label."),
+        },
+    }
     Ok(())
 }
 
-pub fn creat(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
-    filename: &str,
-) -> io::Result<()> {
-    // Check if file already exists
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    for entry in entries {
-        if format_name(&entry.name) == filename {
-            eprintln!("Error: File '{}' already exists.", filename);
-            return Ok(());
+// Shows whether the boot sector and its backup can be used, or copies one
+// over the other: "restore" copies the backup over the boot sector and
+// "backup" the boot sector over the backup
+pub fn bootsector<D: BlockDevice>(fs: &mut Fs<D>, action: Option<&str>) -> Result<()> {
+    let fat32 = fs.fat32_mut();
+    match action {
+        None => {
+            match fat32.boot_sector_problem(0)? {
+                Some(reason) => println!("Boot sector: damaged ({})", reason),
+                None => println!("Boot sector: valid"),
+            }
+            match fat32.backup_boot_sector() {
+                Some(backup) => {
+                    let state = match fat32.boot_sector_problem(backup)? {
+                        Some(reason) => format!("damaged ({})", reason),
+                        None if fat32.backup_boot_sector_matches()? == Some(true) => {
+                            "valid, same as the boot sector".to_string()
+                        }
+                        None => "valid, differs from the boot sector".to_string(),
+                    };
+                    println!("Backup boot sector (sector {}): {}", backup, state);
+                }
+                None => println!("Backup boot sector: none"),
+            }
+        }
+        Some("restore") => {
+            let backup = fat32.restore_boot_sector()?;
+            println!("Boot sector restored from the backup at sector {}.", backup);
+        }
+        Some("backup") => {
+            let backup = fat32.update_backup_boot_sector()?;
+            println!("Boot sector copied to the backup at sector {}.", backup);
+        }
+        Some(action) => {
+            return Err(FsError::InvalidArgument(format!(
+                "Invalid action '{}'; use 'restore' or 'backup'.",
+                action
+            )))
         }
     }
+    Ok(())
+}
 
-    // Create the file
-    fat32.create_file(image_file, current_dir_cluster, filename)?;
+pub fn cd<D: BlockDevice>(fs: &mut Fs<D>, dirname: &str) -> Result<()> {
+    fs.set_current_dir(dirname)
+}
 
+pub fn mkdir<D: BlockDevice>(fs: &mut Fs<D>, dirname: &str) -> Result<()> {
+    fs.create_dir(dirname)?;
+    println!("Directory '{}' created.", dirname);
+    Ok(())
+}
+
+pub fn creat<D: BlockDevice>(fs: &mut Fs<D>, filename: &str) -> Result<()> {
+    fs.create(filename)?;
     println!("File '{}' created.", filename);
     Ok(())
 }
 
-pub fn open(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
+pub fn open<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     flags: &str,
     open_files: &mut OpenFiles,
-) -> io::Result<()> {
-    // Check if file is already open
-    if open_files.is_file_open(filename) {
-        eprintln!("Error: File '{}' is already open.", filename);
-        return Ok(());
-    }
-
+) -> Result<()> {
     // Validate flags
     let mode = match flags {
         "-r" => FileMode::ReadOnly,
         "-w" => FileMode::WriteOnly,
         "-rw" | "-wr" => FileMode::ReadWrite,
-        _ => {
-            eprintln!("Error: Invalid mode '{}'.", flags);
-            return Ok(());
-        }
+        _ => return Err(FsError::InvalidArgument(format!("Invalid mode '{}'.", flags))),
     };
 
     // Find the file in the directory
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    let mut file_entry: Option<DirectoryEntry> = None;
-    for entry in entries {
-        if format_name(&entry.name) == filename && entry.is_file() {
-            file_entry = Some(entry);
-            break;
-        }
+    let entry = fs.lookup(filename)?;
+    if entry.is_directory() {
+        return Err(FsError::IsADirectory(filename.to_string()));
+    }
+    if entry.is_read_only() && mode != FileMode::ReadOnly {
+        return Err(FsError::ReadOnly(filename.to_string()));
     }
 
-    if let Some(entry) = file_entry {
-        let open_file = OpenFile {
-            filename: filename.to_string(),
-            mode,
-            offset: 0,
-            first_cluster: entry.first_cluster,
-            file_size: entry.file_size,
-        };
-        open_files.open_file(open_file).map_err(|e| {
-            eprintln!("{}", e);
-            io::Error::new(io::ErrorKind::Other, e)
-        })?;
-        println!("File '{}' opened.", filename);
-    } else {
-        eprintln!("Error: File '{}' does not exist.", filename);
+    // Check if file is already open
+    if open_files.is_file_open(entry.offset) {
+        return Err(FsError::FileBusy(filename.to_string()));
     }
 
+    let open_file = OpenFile {
+        filename: filename.to_string(),
+        mode,
+        offset: 0,
+        entry,
+    };
+    open_files.open_file(open_file)?;
+    println!("File '{}' opened.", filename);
     Ok(())
 }
 
-pub fn close(filename: &str, open_files: &mut OpenFiles) -> io::Result<()> {
-    open_files.close_file(filename).map_err(|e| {
-        eprintln!("{}", e);
-        io::Error::new(io::ErrorKind::Other, e)
-    })?;
+pub fn close<D: BlockDevice>(
+    fs: &mut Fs<D>,
+    filename: &str,
+    open_files: &mut OpenFiles,
+) -> Result<()> {
+    let entry = fs.lookup(filename)?;
+    open_files
+        .close_file(entry.offset)
+        .ok_or_else(|| FsError::NotOpen(filename.to_string()))?;
     println!("File '{}' closed.", filename);
     Ok(())
 }
 
-pub fn lsof(open_files: &OpenFiles) -> io::Result<()> {
+pub fn lsof(open_files: &OpenFiles) -> Result<()> {
     let open_files_list = open_files.list_open_files();
     if open_files_list.is_empty() {
         println!("No files are open.");
@@ -204,318 +472,345 @@ pub fn lsof(open_files: &OpenFiles) -> io::Result<()> {
     Ok(())
 }
 
-pub fn size(
-    image_file: &mut File,
-    fat32: &FAT32,
-    current_dir_cluster: u32,
-    filename: &str,
-) -> io::Result<()> {
+pub fn size<D: BlockDevice>(fs: &mut Fs<D>, filename: &str) -> Result<()> {
     // Find the file in the directory
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    for entry in entries {
-        if format_name(&entry.name) == filename && entry.is_file() {
-            println!("Size of '{}': {} bytes", filename, entry.file_size);
-            return Ok(());
-        }
+    let entry = fs.lookup(filename)?;
+    if entry.is_directory() {
+        return Err(FsError::IsADirectory(filename.to_string()));
     }
-    eprintln!("Error: File '{}' does not exist or is a directory.", filename);
+    println!("Size of '{}': {} bytes", filename, entry.file_size);
     Ok(())
 }
 
-pub fn lseek(
+pub fn lseek<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     offset_str: &str,
     open_files: &mut OpenFiles,
-) -> io::Result<()> {
-    let offset: u32 = offset_str.parse().unwrap_or_else(|_| {
-        eprintln!("Error: Invalid offset '{}'.", offset_str);
-        0
-    });
-
-    if let Some(open_file) = open_files.get_file_mut(filename) {
-        if offset > open_file.file_size {
-            eprintln!("Error: Offset exceeds file size.");
-            return Ok(());
-        }
-        open_file.offset = offset;
-        println!("Offset of '{}' set to {}.", filename, offset);
-    } else {
-        eprintln!("Error: File '{}' is not open.", filename);
+) -> Result<()> {
+    let offset: u32 = offset_str
+        .parse()
+        .map_err(|_| FsError::InvalidArgument(format!("Invalid offset '{}'.", offset_str)))?;
+
+    let open_file = find_open_file(fs, filename, open_files)?;
+    if offset > open_file.entry.file_size {
+        return Err(FsError::InvalidArgument("Offset exceeds file size.".to_string()));
     }
+    open_file.offset = offset;
+    println!("Offset of '{}' set to {}.", filename, offset);
     Ok(())
 }
 
-pub fn read(
-    image_file: &mut File,
-    fat32: &FAT32,
+pub fn read<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     size_str: &str,
     open_files: &mut OpenFiles,
-) -> io::Result<()> {
-    let size: u32 = size_str.parse().unwrap_or_else(|_| {
-        eprintln!("Error: Invalid size '{}'.", size_str);
-        0
-    });
-
-    if let Some(open_file) = open_files.get_file_mut(filename) {
-        if open_file.mode == FileMode::WriteOnly {
-            eprintln!("Error: File '{}' is not open for reading.", filename);
-            return Ok(());
-        }
+) -> Result<()> {
+    let size: u32 = size_str
+        .parse()
+        .map_err(|_| FsError::InvalidArgument(format!("Invalid size '{}'.", size_str)))?;
+
+    let open_file = find_open_file(fs, filename, open_files)?;
+    if open_file.mode == FileMode::WriteOnly {
+        return Err(FsError::WrongMode {
+            name: filename.to_string(),
+            needed: "reading",
+        });
+    }
 
-        let cluster_chain = fat32.get_cluster_chain(image_file, open_file.first_cluster)?;
-        let data = fat32.read_file_data(
-            image_file,
-            &cluster_chain,
-            open_file.offset,
-            size,
-        )?;
+    let mut file = fs.open_entry(&open_file.entry);
+    file.seek(SeekFrom::Start(open_file.offset as u64))?;
+    let mut data = Vec::new();
+    file.take(size as u64).read_to_end(&mut data)?;
 
-        // Update the offset
-        open_file.offset += data.len() as u32;
+    // Update the offset
+    open_file.offset += data.len() as u32;
 
-        // Print the data as a string
-        if let Ok(string) = String::from_utf8(data) {
-            println!("{}", string);
-        } else {
-            eprintln!("Error: Failed to read data as string.");
-        }
-    } else {
-        eprintln!("Error: File '{}' is not open.", filename);
-    }
+    // Print the data as a string; bytes that aren't UTF-8 show up as U+FFFD
+    println!("{}", String::from_utf8_lossy(&data));
 
     Ok(())
 }
 
-pub fn write(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
+pub fn write<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     string: &str,
     open_files: &mut OpenFiles,
-) -> io::Result<()> {
+) -> Result<()> {
     // Remove quotes from string
     let string = string.trim_matches('"');
     let data = string.as_bytes();
 
-    if let Some(open_file) = open_files.get_file_mut(filename) {
-        if open_file.mode == FileMode::ReadOnly {
-            eprintln!("Error: File '{}' is not open for writing.", filename);
-            return Ok(());
-        }
-
-        let mut cluster_chain =
-            fat32.get_cluster_chain(image_file, open_file.first_cluster)?;
-
-        // Write data
-        fat32.write_file_data(
-            image_file,
-            &mut cluster_chain,
-            open_file.offset,
-            data,
-        )?;
-
-        // Update the offset and file size
-        open_file.offset += data.len() as u32;
-        if open_file.offset > open_file.file_size {
-            open_file.file_size = open_file.offset;
-            // Update the file size in the directory entry
-            fat32.update_file_size(
-                image_file,
-                open_file.first_cluster,
-                current_dir_cluster,
-                open_file.file_size,
-            )?;
-        }
+    // The file may have been made read-only after it was opened
+    if fs.lookup(filename)?.is_read_only() {
+        return Err(FsError::ReadOnly(filename.to_string()));
+    }
 
-        println!("Wrote to '{}'.", filename);
-    } else {
-        eprintln!("Error: File '{}' is not open.", filename);
+    let open_file = find_open_file(fs, filename, open_files)?;
+    if open_file.mode == FileMode::ReadOnly {
+        return Err(FsError::WrongMode {
+            name: filename.to_string(),
+            needed: "writing",
+        });
     }
 
+    // Write data; flushing the handle updates the directory entry
+    let mut file = fs.open_entry(&open_file.entry);
+    file.seek(SeekFrom::Start(open_file.offset as u64))?;
+    let result = file.write_all(data).and_then(|()| file.flush());
+
+    // Update the offset and the cached entry, even after a partial write
+    open_file.entry.first_cluster = file.first_cluster();
+    open_file.entry.file_size = file.size();
+    result?;
+    open_file.offset += data.len() as u32;
+
+    println!("Wrote to '{}'.", filename);
     Ok(())
 }
 
-pub fn rename(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
+pub fn rename<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     new_filename: &str,
     open_files: &OpenFiles,
-) -> io::Result<()> {
-    // Cannot rename '.' or '..'
-    if filename == "." || filename == ".." {
-        eprintln!("Error: Cannot rename special directories '.' or '..'.");
-        return Ok(());
-    }
-
-    // Check if FILENAME exists in current directory
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    let mut entry_to_rename: Option<DirectoryEntry> = None;
-    for entry in &entries {
-        if format_name(&entry.name) == filename {
-            entry_to_rename = Some(entry.clone());
-            break;
+) -> Result<()> {
+    // Check if file is open; a missing file is reported by Fs::rename
+    if let Ok(entry) = fs.lookup(filename) {
+        if open_files.is_file_open(entry.offset) {
+            return Err(FsError::FileBusy(filename.to_string()));
         }
     }
 
-    if entry_to_rename.is_none() {
-        eprintln!("Error: File or directory '{}' does not exist.", filename);
-        return Ok(());
-    }
-
-    // Check if NEW_FILENAME already exists
-    for entry in &entries {
-        if format_name(&entry.name) == new_filename {
-            eprintln!("Error: A file or directory named '{}' already exists.", new_filename);
-            return Ok(());
-        }
-    }
-
-    // Check if file is open
-    if open_files.is_file_open(filename) {
-        eprintln!("Error: File '{}' must be closed before renaming.", filename);
-        return Ok(());
-    }
-
-    // Update the directory entry's name
-    let entry = entry_to_rename.unwrap();
-    fat32.update_entry_name(
-        image_file,
-        current_dir_cluster,
-        &entry,
-        new_filename,
-    )?;
-
+    fs.rename(filename, new_filename)?;
     println!("'{}' renamed to '{}'.", filename, new_filename);
     Ok(())
 }
 
-pub fn rm(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
+pub fn rm<D: BlockDevice>(
+    fs: &mut Fs<D>,
     filename: &str,
     open_files: &OpenFiles,
-) -> io::Result<()> {
+) -> Result<()> {
     // Check if FILENAME exists and is a file
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    let mut file_entry = None;
-    for entry in &entries {
-        if format_name(&entry.name) == filename {
-            if entry.is_directory() {
-                eprintln!("Error: '{}' is a directory.", filename);
-                return Ok(());
-            }
-            file_entry = Some(entry.clone());
-            break;
-        }
-    }
-
-    if file_entry.is_none() {
-        eprintln!("Error: File '{}' does not exist.", filename);
-        return Ok(());
+    let entry = fs.lookup(filename)?;
+    if entry.is_directory() {
+        return Err(FsError::IsADirectory(filename.to_string()));
     }
 
     // Check if file is open
-    if open_files.is_file_open(filename) {
-        eprintln!("Error: File '{}' is open.", filename);
-        return Ok(());
+    if open_files.is_file_open(entry.offset) {
+        return Err(FsError::FileBusy(filename.to_string()));
     }
 
-    // Remove the directory entry from the current directory
-    let file_entry_unwrapped = file_entry.unwrap();
-    fat32.remove_directory_entry(
-        image_file,
-        current_dir_cluster,
-        &file_entry_unwrapped,
-    )?;
-
-    // Free the clusters used by the file
-    fat32.free_cluster_chain(
-        image_file,
-        file_entry_unwrapped.first_cluster,
-    )?;
+    // Remove the directory entry and free the clusters used by the file
+    fs.remove(filename)?;
 
     println!("File '{}' deleted.", filename);
     Ok(())
 }
 
-pub fn rmdir(
-    image_file: &mut File,
-    fat32: &mut FAT32,
-    current_dir_cluster: u32,
-    dirname: &str,
-    #[allow(unused_variables)]
-    open_files: &OpenFiles,
-) -> io::Result<()> {
-    // Cannot remove '.' or '..'
-    if dirname == "." || dirname == ".." {
-        eprintln!("Error: Cannot remove special directories '.' or '..'.");
-        return Ok(());
-    }
+pub fn rmdir<D: BlockDevice>(fs: &mut Fs<D>, dirname: &str) -> Result<()> {
+    fs.remove_dir(dirname)?;
+    println!("Directory '{}' removed.", dirname);
+    Ok(())
+}
 
-    // Check if DIRNAME exists and is a directory
-    let entries = fat32.read_directory_entries(image_file, current_dir_cluster)?;
-    let mut dir_entry = None;
-    for entry in &entries {
-        if format_name(&entry.name) == dirname {
-            if !entry.is_directory() {
-                eprintln!("Error: '{}' is not a directory.", dirname);
-                return Ok(());
+// Shows the attributes of `filename`, after applying `changes` such as `+r`
+// or `-h` when there are any
+pub fn attrib<D: BlockDevice>(fs: &mut Fs<D>, filename: &str, changes: &[&str]) -> Result<()> {
+    let mut entry = fs.lookup(filename)?;
+    if !changes.is_empty() {
+        let mut attr = entry.attr;
+        for change in changes {
+            let bit = match change.get(1..).map(str::to_ascii_lowercase).as_deref() {
+                Some("r") => 0x01,
+                Some("h") => 0x02,
+                Some("s") => 0x04,
+                Some("a") => 0x20,
+                _ => return Err(invalid_attribute(change)),
+            };
+            match change.chars().next() {
+                Some('+') => attr |= bit,
+                Some('-') => attr &= !bit,
+                _ => return Err(invalid_attribute(change)),
             }
-            dir_entry = Some(entry.clone());
-            break;
         }
+        entry.attr = fs.set_attributes(filename, attr)?;
     }
 
-    if dir_entry.is_none() {
-        eprintln!("Error: Directory '{}' does not exist.", dirname);
-        return Ok(());
-    }
+    println!("{}  {}", entry.attr_flags(), filename);
+    Ok(())
+}
+
+fn invalid_attribute(change: &str) -> FsError {
+    FsError::InvalidArgument(format!(
+        "Invalid attribute change '{}'; use +r, -r, +h, -h, +s, -s, +a or -a.",
+        change
+    ))
+}
+
+// Copies a file, or with `recursive` a directory tree, from the host into
+// the image. An existing directory at `image_path` receives a copy under the
+// host name.
+pub fn put<D: BlockDevice>(
+    fs: &mut Fs<D>,
+    host_path: &str,
+    image_path: Option<&str>,
+    recursive: bool,
+) -> Result<()> {
+    let host = Path::new(host_path);
+    let metadata = std::fs::metadata(host).map_err(|e| host_error(host, e))?;
+    let name = match host.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return Err(FsError::InvalidName(host_path.to_string())),
+    };
 
-    // Check if directory is empty (contains only '.' and '..')
-    let dir_cluster = dir_entry.as_ref().unwrap().first_cluster;
-    let dir_entries = fat32.read_directory_entries(image_file, dir_cluster)?;
-    let mut is_empty = true;
-    for entry in &dir_entries {
-        let name = format_name(&entry.name);
-        if name != "." && name != ".." {
-            is_empty = false;
-            break;
+    let target = match image_path {
+        None => name.to_string(),
+        Some(path) if fs.read_dir(path).is_ok() => join_image_path(path, name),
+        Some(path) => path.to_string(),
+    };
+
+    if !metadata.is_dir() && !metadata.is_file() {
+        Err(FsError::InvalidArgument(format!(
+            "'{}' is not a regular file or directory.",
+            host_path
+        )))
+    } else if !metadata.is_dir() {
+        let size = host_copy::copy_file(fs, host, &target)?;
+        println!("Copied '{}' to '{}' ({} bytes).", host.display(), target, size);
+        Ok(())
+    } else if recursive {
+        fs.create_dir(&target)?;
+        println!("Directory '{}' created.", target);
+        let mut skipped = Vec::new();
+        let result = host_copy::copy_children(fs, host, &target, &mut skipped, &mut |_, copied| {
+            match copied.bytes {
+                Some(size) => println!(
+                    "Copied '{}' to '{}' ({} bytes).",
+                    copied.host.display(),
+                    copied.image_path,
+                    size
+                ),
+                None => println!("Directory '{}' created.", copied.image_path),
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            // Don't leave part of the tree behind
+            let _ = host_copy::remove_tree(fs, &target);
         }
+        for path in &skipped {
+            println!("Skipped '{}': not a regular file or directory.", path.display());
+        }
+        result
+    } else {
+        Err(FsError::IsADirectory(host_path.to_string()))
     }
+}
 
-    if !is_empty {
-        eprintln!("Error: Directory '{}' is not empty.", dirname);
-        return Ok(());
+// Copies a file, or with `recursive` a directory tree, from the image to the
+// host. An existing host directory at `host_path` receives a copy under the
+// name from the image. Existing host files are never overwritten.
+pub fn get<D: BlockDevice>(
+    fs: &mut Fs<D>,
+    image_path: &str,
+    host_path: Option<&str>,
+    recursive: bool,
+) -> Result<()> {
+    let is_dir = fs.read_dir(image_path).is_ok();
+    // `/`, `.` and `..` have no name of their own to copy to
+    let name = match fs.lookup(image_path) {
+        Ok(entry) => Some(entry.display_name().to_string()),
+        Err(FsError::IsADirectory(_)) if is_dir => None,
+        Err(e) => return Err(e),
+    };
+
+    let target = match (host_path, name) {
+        (Some(path), Some(name)) if Path::new(path).is_dir() => Path::new(path).join(name),
+        (Some(path), _) => PathBuf::from(path),
+        (None, Some(name)) => PathBuf::from(name),
+        (None, None) => {
+            return Err(FsError::InvalidArgument(format!(
+                "'get' needs a host path to copy '{}' to.",
+                image_path
+            )))
+        }
+    };
+
+    if !is_dir {
+        get_file(fs, image_path, &target)
+    } else if recursive {
+        get_dir(fs, image_path, &target)
+    } else {
+        Err(FsError::IsADirectory(image_path.to_string()))
     }
+}
+
+// Copies the image file `image_path` to a new host file at `host`. A copy
+// that fails partway is removed again.
+fn get_file<D: BlockDevice>(fs: &mut Fs<D>, image_path: &str, host: &Path) -> Result<()> {
+    let mut file = fs.open(image_path)?;
+    let mut host_file = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(host)
+        .map_err(|e| host_error(host, e))?;
+    let result = io::copy(&mut file, &mut host_file);
+    drop(host_file);
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = std::fs::remove_file(host);
+            return Err(e.into());
+        }
+    };
+    println!("Copied '{}' to '{}' ({} bytes).", image_path, host.display(), size);
+    Ok(())
+}
 
-    // Remove the directory entry from the current directory
-    let dir_entry_unwrapped = dir_entry.unwrap();
-    fat32.remove_directory_entry(
-        image_file,
-        current_dir_cluster,
-        &dir_entry_unwrapped,
-    )?;
+// Copies the image directory `image_path` and everything below it to a new
+// host directory at `host`, which is removed again if the copy fails
+fn get_dir<D: BlockDevice>(fs: &mut Fs<D>, image_path: &str, host: &Path) -> Result<()> {
+    std::fs::create_dir(host).map_err(|e| host_error(host, e))?;
+    println!("Directory '{}' created.", host.display());
 
-    // Free the clusters used by the directory
-    fat32.free_cluster_chain(
-        image_file,
-        dir_cluster,
-    )?;
+    let result = get_children(fs, image_path, host);
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(host);
+    }
+    result
+}
 
-    println!("Directory '{}' removed.", dirname);
+fn get_children<D: BlockDevice>(fs: &mut Fs<D>, image_path: &str, host: &Path) -> Result<()> {
+    for entry in fs.read_dir(image_path)? {
+        let name = entry.display_name();
+        // Skip the entries every directory has
+        if name == "." || name == ".." {
+            continue;
+        }
+        let image_child = join_image_path(image_path, name);
+        let host_child = host.join(name);
+        if entry.is_directory() {
+            get_dir(fs, &image_child, &host_child)?;
+        } else {
+            get_file(fs, &image_child, &host_child)?;
+        }
+    }
     Ok(())
 }
 
-// Helper function to format the 11-byte name
-fn format_name(name: &str) -> String {
-    let mut formatted = name.to_string();
-    formatted = formatted.trim().to_string();
-    formatted = formatted.replace(" ", "");
-    formatted = formatted.trim_matches(char::from(0)).to_string();
-    formatted
+// Looks up the open file `filename` refers to
+fn find_open_file<'a, D: BlockDevice>(
+    fs: &mut Fs<D>,
+    filename: &str,
+    open_files: &'a mut OpenFiles,
+) -> Result<&'a mut OpenFile> {
+    let entry = fs.lookup(filename)?;
+    open_files
+        .get_file_mut(entry.offset)
+        .ok_or_else(|| FsError::NotOpen(filename.to_string()))
 }
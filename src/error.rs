@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong in the file system layer. The shell turns
+// each variant into a fixed message.
+#[derive(Debug)]
+pub enum FsError {
+    NotFound(String),
+    AlreadyExists(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    DirectoryNotEmpty(String),
+    FileBusy(String),
+    ReadOnly(String),
+    NotOpen(String),
+    TooManyOpenFiles,
+    // The file is open, but not in a mode that allows the operation
+    WrongMode { name: String, needed: &'static str },
+    NoSpace,
+    InvalidName(String),
+    InvalidArgument(String),
+    Corrupted { reason: String },
+    // The image doesn't hold a FAT32 file system that can be mounted
+    NotFat32(String),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, FsError>;
+
+impl FsError {
+    pub fn corrupted<S: Into<String>>(reason: S) -> Self {
+        FsError::Corrupted {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound(name) => write!(f, "'{}' does not exist.", name),
+            FsError::AlreadyExists(name) => write!(f, "'{}' already exists.", name),
+            FsError::NotADirectory(name) => write!(f, "'{}' is not a directory.", name),
+            FsError::IsADirectory(name) => write!(f, "'{}' is a directory.", name),
+            FsError::DirectoryNotEmpty(name) => write!(f, "Directory '{}' is not empty.", name),
+            FsError::FileBusy(name) => write!(f, "File '{}' is open.", name),
+            FsError::ReadOnly(name) => write!(f, "'{}' is read-only.", name),
+            FsError::NotOpen(name) => write!(f, "File '{}' is not open.", name),
+            FsError::TooManyOpenFiles => write!(f, "Maximum number of open files reached."),
+            FsError::WrongMode { name, needed } => {
+                write!(f, "File '{}' is not open for {}.", name, needed)
+            }
+            FsError::NoSpace => write!(f, "No space left on the volume."),
+            FsError::InvalidName(name) => write!(f, "Invalid name '{}'.", name),
+            FsError::InvalidArgument(message) => write!(f, "{}", message),
+            FsError::Corrupted { reason } => write!(f, "File system is corrupted: {}", reason),
+            FsError::NotFat32(reason) => write!(f, "Not a valid FAT32 file system: {}", reason),
+            FsError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(e: io::Error) -> Self {
+        // Unwrap errors that passed through an io::Read/Write impl
+        if e.get_ref().is_some_and(|inner| inner.is::<FsError>()) {
+            return *e.into_inner().unwrap().downcast::<FsError>().unwrap();
+        }
+        FsError::Io(e)
+    }
+}
+
+impl From<FsError> for io::Error {
+    fn from(e: FsError) -> Self {
+        let kind = match e {
+            FsError::Io(inner) => return inner,
+            FsError::NotFound(_) | FsError::NotOpen(_) => io::ErrorKind::NotFound,
+            FsError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            FsError::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            FsError::IsADirectory(_) => io::ErrorKind::IsADirectory,
+            FsError::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            FsError::FileBusy(_) => io::ErrorKind::ResourceBusy,
+            FsError::TooManyOpenFiles => io::ErrorKind::QuotaExceeded,
+            FsError::ReadOnly(_) | FsError::WrongMode { .. } => io::ErrorKind::PermissionDenied,
+            FsError::NoSpace => io::ErrorKind::StorageFull,
+            FsError::InvalidName(_) | FsError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+            FsError::Corrupted { .. } | FsError::NotFat32(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -1,35 +1,92 @@
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, Result};
+use crate::timestamp::Timestamp;
 
 pub struct BootSector {
+    pub jump: [u8; 3],
+    pub oem_name: String,
     pub bytes_per_sector: u16,
     pub sectors_per_cluster: u8,
     pub reserved_sector_count: u16,
     pub num_fats: u8,
+    // Always 0 on FAT32
+    pub root_entry_count: u16,
+    pub total_sectors_16: u16,
+    pub media: u8,
+    pub fat_size_16: u16,
+    pub sectors_per_track: u16,
+    pub num_heads: u16,
+    pub hidden_sectors: u32,
+    pub total_sectors_32: u32,
+    // The 16-bit or 32-bit total, whichever is set
     pub total_sectors: u32,
+    // Sectors per FAT; FAT32 leaves the 16-bit field at 0
     pub fat_size_32: u32,
+    pub ext_flags: u16,
+    pub fs_version: u16,
     pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    pub drive_number: u8,
+    // 0x29 when the serial number, label and type fields are present
+    pub boot_signature: u8,
+    pub volume_id: u32,
+    // Label from the boot sector, without the padding
+    pub volume_label: String,
+    // Informational only, normally "FAT32"
+    pub fs_type: String,
     pub signature: u16,
 }
 
-pub struct FAT32 {
+// Contents of the FSInfo sector. Both values are hints: 0xFFFFFFFF means unknown.
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+pub struct FAT32<D: BlockDevice> {
+    device: D,
     pub boot_sector: BootSector,
     pub total_clusters: u32,
     pub fat_offset: u64,
     pub data_region_offset: u64,
+    // None when the FSInfo sector is missing or has bad signatures
+    pub fs_info: Option<FsInfo>,
+    // Problems with the boot sector found while mounting, for the user to fix
+    pub mount_warnings: Vec<String>,
+    fat: FatCache,
 }
 
-impl FAT32 {
-    pub fn new(image_file: &mut File) -> io::Result<Self> {
-        let boot_sector = Self::read_boot_sector(image_file)?;
-
-        // Validate FAT32 signature
-        if boot_sector.signature != 0xAA55 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid FAT32 signature.",
-            ));
+pub(crate) const FSINFO_LEAD_SIG: u32 = 0x41615252;
+pub(crate) const FSINFO_STRUCT_SIG: u32 = 0x61417272;
+pub(crate) const FSINFO_TRAIL_SIG: u32 = 0xAA550000;
+
+impl<D: BlockDevice> FAT32<D> {
+    pub fn new(mut device: D) -> Result<Self> {
+        let image_size = device.size()?;
+        if image_size < 512 {
+            return Err(FsError::NotFat32(format!(
+                "the image is only {} bytes long",
+                image_size
+            )));
         }
+        let primary = Self::read_boot_sector(&mut device, 0)?;
+        let mut mount_warnings = Vec::new();
+        let mut from_backup = false;
+        let (boot_sector, total_clusters) = match validate_boot_sector(&primary, image_size) {
+            Ok(total_clusters) => (primary, total_clusters),
+            Err(reason) => match Self::find_backup_boot_sector(&mut device, image_size)? {
+                Some((sector, backup, total_clusters)) => {
+                    mount_warnings.push(format!(
+                        "The boot sector is damaged ({}); using the backup boot sector at sector {}. Run 'bootsector restore' to repair it.",
+                        reason, sector
+                    ));
+                    from_backup = true;
+                    (backup, total_clusters)
+                }
+                None => return Err(FsError::NotFat32(reason)),
+            },
+        };
 
         let fat_offset =
             (boot_sector.reserved_sector_count as u64) * (boot_sector.bytes_per_sector as u64);
@@ -39,24 +96,325 @@ impl FAT32 {
                 * (boot_sector.fat_size_32 as u64)
                 * (boot_sector.bytes_per_sector as u64);
 
-        // Total number of clusters
-        let total_clusters = (boot_sector.total_sectors
-            - boot_sector.reserved_sector_count as u32
-            - ((boot_sector.num_fats as u32 * boot_sector.fat_size_32)))
-            / boot_sector.sectors_per_cluster as u32;
-
-        Ok(FAT32 {
+        let mut fat32 = FAT32 {
+            device,
             boot_sector,
             total_clusters,
             fat_offset,
             data_region_offset,
+            fs_info: None,
+            mount_warnings,
+            fat: FatCache::default(),
+        };
+
+        fat32.fat = fat32.load_fat()?;
+        fat32.fs_info = fat32.read_fs_info()?;
+        if let Some(fs_info) = &mut fat32.fs_info {
+            // The cached FAT is authoritative; a stale count is fixed on the next flush
+            fs_info.free_count = fat32.fat.free_count;
+        }
+        if !from_backup && fat32.backup_boot_sector_matches()? == Some(false) {
+            let sector = fat32.boot_sector.backup_boot_sector;
+            fat32.mount_warnings.push(format!(
+                "The backup boot sector (sector {}) differs from the boot sector. Run 'bootsector backup' to update it.",
+                sector
+            ));
+        }
+
+        Ok(fat32)
+    }
+
+    // Looks for a usable backup boot sector at sector 6, where formatting
+    // tools put it. The sector size isn't known when the boot sector is
+    // damaged, so each one is tried. Returns the sector, the boot sector
+    // read from it and its number of clusters.
+    fn find_backup_boot_sector(
+        device: &mut D,
+        image_size: u64,
+    ) -> Result<Option<(u64, BootSector, u32)>> {
+        const SECTOR: u64 = 6;
+        for bytes_per_sector in [512u16, 1024, 2048, 4096] {
+            let offset = SECTOR * bytes_per_sector as u64;
+            if offset + 512 > image_size {
+                break;
+            }
+            let backup = Self::read_boot_sector(device, offset)?;
+            if backup.bytes_per_sector != bytes_per_sector
+                || backup.backup_boot_sector as u64 != SECTOR
+            {
+                continue;
+            }
+            if let Ok(total_clusters) = validate_boot_sector(&backup, image_size) {
+                return Ok(Some((SECTOR, backup, total_clusters)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads the active FAT into memory
+    fn load_fat(&mut self) -> Result<FatCache> {
+        let fat_bytes = (self.boot_sector.fat_size_32 as u64)
+            * (self.boot_sector.bytes_per_sector as u64);
+        let needed_bytes = (self.max_cluster() as u64 + 1) * 4;
+        let mut buffer = vec![0u8; std::cmp::min(fat_bytes, needed_bytes) as usize];
+        self.device.read_at(self.fat_copy_offset(self.active_fat()), &mut buffer)?;
+
+        let mut entries: Vec<u32> = buffer
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect();
+        // Clusters the FAT is too small to describe can never be used
+        entries.resize(self.max_cluster() as usize + 1, 0x0FFFFFF7);
+
+        Ok(FatCache::new(entries))
+    }
+
+    // Writes modified FAT entries to every FAT copy and updates FSInfo
+    pub fn flush(&mut self) -> Result<()> {
+        let (first, last) = match self.fat.dirty.take() {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        let mut buffer = Vec::with_capacity(((last - first + 1) * 4) as usize);
+        for value in &self.fat.entries[first as usize..=last as usize] {
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in self.fat_write_targets() {
+            self.device.write_at(self.fat_copy_offset(index) + (first * 4) as u64, &buffer)?;
+        }
+
+        if let Some(fs_info) = &mut self.fs_info {
+            fs_info.free_count = self.fat.free_count;
+        }
+        self.write_fs_info()?;
+        self.device.flush()?;
+        Ok(())
+    }
+
+    // Reads the FSInfo sector as stored on disk. Returns None when it is
+    // missing or its signatures are wrong.
+    pub fn read_fs_info(&mut self) -> Result<Option<FsInfo>> {
+        let sector = self.boot_sector.fs_info_sector;
+        if sector == 0 || sector == 0xFFFF || sector >= self.boot_sector.reserved_sector_count {
+            return Ok(None);
+        }
+
+        let mut buffer = [0u8; 512];
+        self.device.read_at(self.fs_info_offset(), &mut buffer)?;
+
+        let read_u32 = |pos: usize| {
+            u32::from_le_bytes([buffer[pos], buffer[pos + 1], buffer[pos + 2], buffer[pos + 3]])
+        };
+        if read_u32(0) != FSINFO_LEAD_SIG
+            || read_u32(484) != FSINFO_STRUCT_SIG
+            || read_u32(508) != FSINFO_TRAIL_SIG
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(FsInfo {
+            free_count: read_u32(488),
+            next_free: read_u32(492),
+        }))
+    }
+
+    // Writes the free count and next free hint back to the FSInfo sector
+    fn write_fs_info(&mut self) -> Result<()> {
+        if let Some(fs_info) = &self.fs_info {
+            let mut buffer = [0u8; 8];
+            buffer[0..4].copy_from_slice(&fs_info.free_count.to_le_bytes());
+            buffer[4..8].copy_from_slice(&fs_info.next_free.to_le_bytes());
+            self.device.write_at(self.fs_info_offset() + 488, &buffer)?;
+        }
+        Ok(())
+    }
+
+    fn fs_info_offset(&self) -> u64 {
+        (self.boot_sector.fs_info_sector as u64) * (self.boot_sector.bytes_per_sector as u64)
+    }
+
+    // Highest valid cluster number
+    pub fn max_cluster(&self) -> u32 {
+        self.total_clusters + 1
+    }
+
+    // Number of free clusters
+    pub fn free_clusters(&self) -> u32 {
+        self.fat.free_count
+    }
+
+    // Sector holding the backup boot sector, or None when the boot sector
+    // doesn't name one inside the reserved area
+    pub fn backup_boot_sector(&self) -> Option<u64> {
+        let sector = self.boot_sector.backup_boot_sector;
+        if sector == 0 || sector == 0xFFFF || sector >= self.boot_sector.reserved_sector_count {
+            return None;
+        }
+        Some(sector as u64)
+    }
+
+    // Whether the backup boot sector is an exact copy of the boot sector.
+    // Returns None when there is no backup.
+    pub fn backup_boot_sector_matches(&mut self) -> Result<Option<bool>> {
+        let backup = match self.backup_boot_sector() {
+            Some(backup) => backup,
+            None => return Ok(None),
+        };
+        let sector_size = self.boot_sector.bytes_per_sector as usize;
+        let mut primary_sector = vec![0u8; sector_size];
+        let mut backup_sector = vec![0u8; sector_size];
+        self.device.read_sector(0, &mut primary_sector)?;
+        self.device.read_sector(backup, &mut backup_sector)?;
+        Ok(Some(primary_sector == backup_sector))
+    }
+
+    // Why the boot sector copy at `sector` can't be used to mount this
+    // volume, or None when it can
+    pub fn boot_sector_problem(&mut self, sector: u64) -> Result<Option<String>> {
+        let offset = sector * self.boot_sector.bytes_per_sector as u64;
+        let copy = Self::read_boot_sector(&mut self.device, offset)?;
+        let image_size = self.device.size()?;
+        if let Err(reason) = validate_boot_sector(&copy, image_size) {
+            return Ok(Some(reason));
+        }
+        let bs = &self.boot_sector;
+        let same_layout = copy.bytes_per_sector == bs.bytes_per_sector
+            && copy.sectors_per_cluster == bs.sectors_per_cluster
+            && copy.reserved_sector_count == bs.reserved_sector_count
+            && copy.num_fats == bs.num_fats
+            && copy.fat_size_32 == bs.fat_size_32
+            && copy.total_sectors == bs.total_sectors
+            && copy.root_cluster == bs.root_cluster;
+        if !same_layout {
+            return Ok(Some("it describes a different layout than the mounted volume".to_string()));
+        }
+        Ok(None)
+    }
+
+    // Copies the backup boot sector over the boot sector. Returns the
+    // backup's sector.
+    pub fn restore_boot_sector(&mut self) -> Result<u64> {
+        let backup = self.require_backup_boot_sector()?;
+        if let Some(reason) = self.boot_sector_problem(backup)? {
+            return Err(FsError::corrupted(format!(
+                "the backup boot sector (sector {}) can't be used: {}",
+                backup, reason
+            )));
+        }
+        self.copy_sector(backup, 0)?;
+        self.boot_sector = Self::read_boot_sector(&mut self.device, 0)?;
+        Ok(backup)
+    }
+
+    // Copies the boot sector over the backup boot sector. Returns the
+    // backup's sector.
+    pub fn update_backup_boot_sector(&mut self) -> Result<u64> {
+        let backup = self.require_backup_boot_sector()?;
+        if let Some(reason) = self.boot_sector_problem(0)? {
+            return Err(FsError::corrupted(format!(
+                "the boot sector can't be used ({}); restore it from the backup first",
+                reason
+            )));
+        }
+        self.copy_sector(0, backup)?;
+        Ok(backup)
+    }
+
+    fn require_backup_boot_sector(&self) -> Result<u64> {
+        self.backup_boot_sector().ok_or_else(|| {
+            FsError::InvalidArgument("The volume has no backup boot sector.".to_string())
         })
     }
 
-    fn read_boot_sector(image_file: &mut File) -> io::Result<BootSector> {
+    fn copy_sector(&mut self, from: u64, to: u64) -> Result<()> {
+        let mut sector = vec![0u8; self.boot_sector.bytes_per_sector as usize];
+        self.device.read_sector(from, &mut sector)?;
+        self.device.write_sector(to, &sector)?;
+        Ok(())
+    }
+
+    // Things about the boot sector and FSInfo that don't stop the volume
+    // from being used but are unusual or inconsistent, one message each
+    pub fn anomalies(&mut self) -> Result<Vec<String>> {
+        // FSInfo on disk is compared with the FAT, so bring it up to date
+        self.flush()?;
+        let mut anomalies = Vec::new();
+        let bs = &self.boot_sector;
+
+        if self.bytes_per_cluster() > 32 * 1024 {
+            anomalies.push(format!(
+                "Clusters of {} bytes are larger than many systems support (32768).",
+                self.bytes_per_cluster()
+            ));
+        }
+        if bs.root_entry_count != 0 || bs.total_sectors_16 != 0 {
+            anomalies.push(
+                "The FAT12/16 root entry count or total sectors field is not 0.".to_string(),
+            );
+        }
+        let fat_entries = bs.fat_size_32 as u64 * bs.bytes_per_sector as u64 / 4;
+        if fat_entries < self.max_cluster() as u64 + 1 {
+            anomalies.push(format!(
+                "The FAT has {} entries, too few for {} clusters.",
+                fat_entries, self.total_clusters
+            ));
+        }
+        if bs.fs_version != 0 {
+            anomalies.push(format!("Unknown file system version {:#06x}.", bs.fs_version));
+        }
+        if bs.boot_signature != 0x29 {
+            anomalies.push(format!(
+                "Extended boot signature is {:#04x}, not 0x29; the serial number, label and type may be missing.",
+                bs.boot_signature
+            ));
+        } else if bs.fs_type != "FAT32" {
+            anomalies.push(format!("File system type string is '{}', not 'FAT32'.", bs.fs_type));
+        }
+        if !self.fat_mirroring() && self.active_fat() >= bs.num_fats {
+            anomalies.push(format!(
+                "The active FAT is {}, but there are only {} FATs.",
+                self.active_fat(),
+                bs.num_fats
+            ));
+        }
+
+        match self.backup_boot_sector_matches()? {
+            None => anomalies.push("There is no backup boot sector.".to_string()),
+            Some(false) => anomalies.push(format!(
+                "The backup boot sector (sector {}) differs from the boot sector.",
+                self.boot_sector.backup_boot_sector
+            )),
+            Some(true) => {}
+        }
+
+        match self.read_fs_info()? {
+            None => anomalies.push("The FSInfo sector is missing or invalid.".to_string()),
+            Some(fs_info) => {
+                if fs_info.free_count != 0xFFFFFFFF && fs_info.free_count != self.fat.free_count {
+                    anomalies.push(format!(
+                        "FSInfo free cluster count is {}, but the FAT has {} free clusters.",
+                        fs_info.free_count, self.fat.free_count
+                    ));
+                }
+                if fs_info.next_free != 0xFFFFFFFF
+                    && (fs_info.next_free < 2 || fs_info.next_free > self.max_cluster())
+                {
+                    anomalies.push(format!(
+                        "FSInfo next free cluster hint {} is out of range.",
+                        fs_info.next_free
+                    ));
+                }
+            }
+        }
+
+        Ok(anomalies)
+    }
+
+    // Parses the boot sector stored at byte `offset`
+    fn read_boot_sector(device: &mut D, offset: u64) -> Result<BootSector> {
         let mut buffer = [0u8; 512];
-        image_file.seek(SeekFrom::Start(0))?;
-        image_file.read_exact(&mut buffer)?;
+        device.read_at(offset, &mut buffer)?;
 
         let bytes_per_sector = u16::from_le_bytes([buffer[11], buffer[12]]);
         let sectors_per_cluster = buffer[13];
@@ -75,25 +433,53 @@ impl FAT32 {
         let fat_size_16 = u16::from_le_bytes([buffer[22], buffer[23]]);
         let fat_size_32 =
             u32::from_le_bytes([buffer[36], buffer[37], buffer[38], buffer[39]]);
-        let fat_size = if fat_size_16 != 0 {
-            fat_size_16 as u32
-        } else {
-            fat_size_32
-        };
+
+        let ext_flags = u16::from_le_bytes([buffer[40], buffer[41]]);
+        let fs_version = u16::from_le_bytes([buffer[42], buffer[43]]);
 
         let root_cluster =
             u32::from_le_bytes([buffer[44], buffer[45], buffer[46], buffer[47]]);
 
+        let fs_info_sector = u16::from_le_bytes([buffer[48], buffer[49]]);
+        let backup_boot_sector = u16::from_le_bytes([buffer[50], buffer[51]]);
+
+        let text = |range: std::ops::Range<usize>| {
+            String::from_utf8_lossy(&buffer[range]).trim_end().to_string()
+        };
+        let oem_name = text(3..11);
+        let volume_id = u32::from_le_bytes([buffer[67], buffer[68], buffer[69], buffer[70]]);
+        let volume_label = text(71..82);
+        let fs_type = text(82..90);
+
         let signature = u16::from_le_bytes([buffer[510], buffer[511]]);
 
         Ok(BootSector {
+            jump: [buffer[0], buffer[1], buffer[2]],
+            oem_name,
             bytes_per_sector,
             sectors_per_cluster,
             reserved_sector_count,
             num_fats,
+            root_entry_count: u16::from_le_bytes([buffer[17], buffer[18]]),
+            total_sectors_16,
+            media: buffer[21],
+            fat_size_16,
+            sectors_per_track: u16::from_le_bytes([buffer[24], buffer[25]]),
+            num_heads: u16::from_le_bytes([buffer[26], buffer[27]]),
+            hidden_sectors: u32::from_le_bytes([buffer[28], buffer[29], buffer[30], buffer[31]]),
+            total_sectors_32,
             total_sectors,
-            fat_size_32: fat_size,
+            fat_size_32,
+            ext_flags,
+            fs_version,
             root_cluster,
+            fs_info_sector,
+            backup_boot_sector,
+            drive_number: buffer[64],
+            boot_signature: buffer[66],
+            volume_id,
+            volume_label,
+            fs_type,
             signature,
         })
     }
@@ -107,23 +493,31 @@ impl FAT32 {
     }
 
     pub fn read_directory_entries(
-        &self,
-        image_file: &mut File,
-        mut cluster: u32,
-    ) -> io::Result<Vec<DirectoryEntry>> {
+        &mut self,
+        dir_cluster: u32,
+    ) -> Result<Vec<DirectoryEntry>> {
+        check_directory_cluster(dir_cluster)?;
+        let cluster_chain = self.get_cluster_chain(dir_cluster)?;
+        self.read_directory_clusters(&cluster_chain)
+    }
+
+    // Reads the entries stored in the given directory clusters, stopping at
+    // the end-of-directory marker
+    pub fn read_directory_clusters(
+        &mut self,
+        cluster_chain: &[u32],
+    ) -> Result<Vec<DirectoryEntry>> {
         let mut entries = Vec::new();
+        let mut lfn = LongNameBuilder::new();
+        let cluster_size = self.bytes_per_cluster() as u64;
 
-        loop {
+        for &cluster in cluster_chain {
             let cluster_offset = self.cluster_to_offset(cluster);
-
             let mut offset = cluster_offset;
-            let cluster_size = (self.boot_sector.sectors_per_cluster as u64)
-                * (self.boot_sector.bytes_per_sector as u64);
 
             while offset < cluster_offset + cluster_size {
-                image_file.seek(SeekFrom::Start(offset))?;
                 let mut buffer = [0u8; 32];
-                image_file.read_exact(&mut buffer)?;
+                self.device.read_at(offset, &mut buffer)?;
 
                 if buffer[0] == 0x00 {
                     // No more entries
@@ -131,21 +525,23 @@ impl FAT32 {
                 }
 
                 if buffer[0] == 0xE5 {
-                    // Deleted entry, skip
+                    // Deleted entry, skip (and drop any long name in progress)
+                    lfn.reset();
                     offset += 32;
                     continue;
                 }
 
                 let attr = buffer[11];
                 if attr == 0x0F {
-                    // Long file name entry, skip
+                    // Long file name entry, collect it for the following short entry
+                    lfn.push(&buffer, offset);
                     offset += 32;
                     continue;
                 }
 
-                let name = String::from_utf8_lossy(&buffer[0..11])
-                    .trim()
-                    .to_string();
+                let mut short_name = [0u8; 11];
+                short_name.copy_from_slice(&buffer[0..11]);
+                let (long_name, lfn_offsets) = lfn.finish(&short_name);
 
                 let first_cluster_high = u16::from_le_bytes([buffer[20], buffer[21]]);
                 let first_cluster_low = u16::from_le_bytes([buffer[26], buffer[27]]);
@@ -153,147 +549,223 @@ impl FAT32 {
                     ((first_cluster_high as u32) << 16) | first_cluster_low as u32;
                 let file_size =
                     u32::from_le_bytes([buffer[28], buffer[29], buffer[30], buffer[31]]);
+                let field = |at: usize| u16::from_le_bytes([buffer[at], buffer[at + 1]]);
 
                 let entry = DirectoryEntry {
-                    name,
+                    name: format_short_name(&short_name),
+                    long_name,
                     attr,
                     first_cluster,
                     file_size,
+                    created: Timestamp::from_fat(field(16), field(14)).map(|mut time| {
+                        // Odd seconds are kept in the 10 ms field
+                        time.second += std::cmp::min(buffer[13], 199) / 100;
+                        time
+                    }),
+                    modified: Timestamp::from_fat(field(24), field(22)),
+                    accessed: Timestamp::from_fat(field(18), 0),
+                    offset,
+                    lfn_offsets,
                 };
 
                 entries.push(entry);
 
                 offset += 32;
             }
-
-            // Get next cluster in the chain
-            cluster = self.get_next_cluster(image_file, cluster)?;
-
-            // Check if end of cluster chain
-            if cluster >= 0x0FFFFFF8 {
-                break;
-            }
         }
 
         Ok(entries)
     }
 
-    pub fn get_next_cluster(&self, image_file: &mut File, cluster: u32) -> io::Result<u32> {
-        let fat_offset = self.fat_offset + (cluster * 4) as u64;
-        image_file.seek(SeekFrom::Start(fat_offset))?;
-        let mut buffer = [0u8; 4];
-        image_file.read_exact(&mut buffer)?;
-        let next_cluster = u32::from_le_bytes(buffer) & 0x0FFFFFFF;
-        Ok(next_cluster)
+    // Finds the entry called `name` in the directory at `dir_cluster`
+    pub fn find_entry(
+        &mut self,
+        dir_cluster: u32,
+        name: &str,
+    ) -> Result<Option<DirectoryEntry>> {
+        Ok(self
+            .read_directory_entries(dir_cluster)?
+            .into_iter()
+            .find(|entry| !entry.is_volume_label() && entry.matches(name)))
     }
 
-    pub fn read_file_data(
-        &self,
-        image_file: &mut File,
-        cluster_chain: &[u32],
-        offset: u32,
-        size: u32,
-    ) -> io::Result<Vec<u8>> {
-        let mut data = Vec::new();
-        let bytes_per_cluster = self.bytes_per_cluster();
-
-        let mut remaining_size = size;
-        let current_offset = offset;
-        let mut cluster_index = (current_offset / bytes_per_cluster) as usize;
-        let mut cluster_offset = current_offset % bytes_per_cluster;
-
-        while remaining_size > 0 && cluster_index < cluster_chain.len() {
-            let cluster = cluster_chain[cluster_index];
-            let cluster_start = self.cluster_to_offset(cluster);
-
-            let to_read = std::cmp::min(
-                remaining_size,
-                bytes_per_cluster - cluster_offset,
-            ) as usize;
+    // The volume label entry in the root directory, if there is one
+    pub fn volume_label_entry(&mut self) -> Result<Option<DirectoryEntry>> {
+        Ok(self
+            .read_directory_entries(self.boot_sector.root_cluster)?
+            .into_iter()
+            .find(|entry| entry.is_volume_label()))
+    }
 
-            image_file.seek(SeekFrom::Start(
-                cluster_start + cluster_offset as u64,
-            ))?;
+    // Label of the volume: the root directory entry takes precedence over
+    // the boot sector, as on other systems
+    pub fn volume_label(&mut self) -> Result<Option<String>> {
+        if let Some(entry) = self.volume_label_entry()? {
+            // Read the raw name; labels have no extension
+            let mut label = [0u8; 11];
+            self.device.read_at(entry.offset, &mut label)?;
+            return Ok(Some(String::from_utf8_lossy(&label).trim_end().to_string()));
+        }
+        match self.boot_sector.volume_label.as_str() {
+            "" | "NO NAME" => Ok(None),
+            label => Ok(Some(label.to_string())),
+        }
+    }
 
-            let mut buffer = vec![0u8; to_read];
-            image_file.read_exact(&mut buffer)?;
-            data.extend(buffer);
+    // Stores `label` in the boot sector, its backup and the root directory
+    pub fn set_volume_label(&mut self, label: &str) -> Result<()> {
+        let bytes = volume_label_bytes(Some(label))?;
+        let mut sectors = vec![0];
+        sectors.extend(self.backup_boot_sector());
+        for sector in sectors {
+            let offset = sector * self.boot_sector.bytes_per_sector as u64 + 71;
+            self.device.write_at(offset, &bytes)?;
+        }
+        self.boot_sector.volume_label = String::from_utf8_lossy(&bytes).trim_end().to_string();
 
-            remaining_size -= to_read as u32;
-            cluster_offset = 0;
-            cluster_index += 1;
+        match self.volume_label_entry()? {
+            Some(entry) => {
+                self.device.write_at(entry.offset, &bytes)?;
+                self.set_entry_times(entry.offset, None, Some(Timestamp::now()), None)?;
+            }
+            None => {
+                let entry = self.create_directory_entry(&bytes, 0x08, 0, 0);
+                let root_cluster = self.boot_sector.root_cluster;
+                let slot = self.find_free_entry_slots(root_cluster, 1)?[0];
+                self.device.write_at(slot, &entry)?;
+            }
         }
+        Ok(())
+    }
 
-        Ok(data)
+    // Cluster of the parent of the directory at `dir_cluster`. A '..' entry
+    // pointing at cluster 0 means the root directory, whose parent is itself.
+    pub fn parent_directory(&mut self, dir_cluster: u32) -> Result<u32> {
+        let root_cluster = self.boot_sector.root_cluster;
+        if dir_cluster == root_cluster {
+            return Ok(root_cluster);
+        }
+        match self.find_entry(dir_cluster, "..")? {
+            Some(entry) if entry.first_cluster == 0 => Ok(root_cluster),
+            Some(entry) => Ok(entry.first_cluster),
+            None => Err(FsError::corrupted(format!(
+                "directory at cluster {} has no '..' entry",
+                dir_cluster
+            ))),
+        }
     }
 
-    pub fn write_file_data(
-        &mut self,
-        image_file: &mut File,
-        cluster_chain: &mut Vec<u32>,
-        offset: u32,
-        data: &[u8],
-    ) -> io::Result<()> {
-        let bytes_per_cluster = self.bytes_per_cluster();
-
-        let mut remaining_size = data.len() as u32;
-        let current_offset = offset;
-        let mut data_offset = 0usize;
-
-        // Ensure the cluster chain is long enough
-        let required_size = offset + remaining_size;
-        let required_clusters = (required_size + bytes_per_cluster - 1)
-            / bytes_per_cluster;
-
-        while (cluster_chain.len() as u32) < required_clusters {
-            let new_cluster = self.allocate_cluster(image_file)?;
-            if let Some(last_cluster) = cluster_chain.last() {
-                self.set_next_cluster(image_file, *last_cluster, new_cluster)?;
+    // Cluster of the directory `path` names. Relative paths start at
+    // `cwd`; empty components and '.' are skipped.
+    pub fn resolve_directory(&mut self, cwd: u32, path: &str) -> Result<u32> {
+        let mut cluster = if path.starts_with('/') {
+            self.boot_sector.root_cluster
+        } else {
+            cwd
+        };
+
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => cluster = self.parent_directory(cluster)?,
+                name => {
+                    let entry = self
+                        .find_entry(cluster, name)?
+                        .ok_or_else(|| FsError::NotFound(path.to_string()))?;
+                    if !entry.is_directory() {
+                        return Err(FsError::NotADirectory(path.to_string()));
+                    }
+                    cluster = check_directory_cluster(entry.first_cluster)?;
+                }
             }
-            cluster_chain.push(new_cluster);
         }
 
-        // Write data
-        let mut cluster_index = (current_offset / bytes_per_cluster) as usize;
-        let mut cluster_offset = current_offset % bytes_per_cluster;
+        Ok(cluster)
+    }
 
-        while remaining_size > 0 && cluster_index < cluster_chain.len() {
-            let cluster = cluster_chain[cluster_index];
-            let cluster_start = self.cluster_to_offset(cluster);
+    // Resolves everything but the last component of `path`. Returns the
+    // directory holding the last component and the component itself, which
+    // is empty when `path` names the root directory.
+    pub fn resolve_parent<'p>(&mut self, cwd: u32, path: &'p str) -> Result<(u32, &'p str)> {
+        let (dir_path, name) = split_path(path);
+        let dir_cluster = self.resolve_directory(cwd, dir_path)?;
+        Ok((dir_cluster, name))
+    }
 
-            let to_write = std::cmp::min(
-                remaining_size,
-                bytes_per_cluster - cluster_offset,
-            ) as usize;
+    // Whether FAT writes go to every copy (BPB_ExtFlags bit 7 clear)
+    pub fn fat_mirroring(&self) -> bool {
+        self.boot_sector.ext_flags & 0x80 == 0
+    }
 
-            image_file.seek(SeekFrom::Start(
-                cluster_start + cluster_offset as u64,
-            ))?;
+    // Index of the FAT that is read from: FAT #0 when mirroring, otherwise
+    // the one selected by BPB_ExtFlags bits 0-3
+    pub fn active_fat(&self) -> u8 {
+        if self.fat_mirroring() {
+            0
+        } else {
+            (self.boot_sector.ext_flags & 0x0F) as u8
+        }
+    }
+
+    // First sector of FAT copy number `index`
+    pub fn fat_copy_sector(&self, index: u8) -> u64 {
+        (self.boot_sector.reserved_sector_count as u64)
+            + (index as u64) * (self.boot_sector.fat_size_32 as u64)
+    }
 
-            image_file.write_all(
-                &data[data_offset..data_offset + to_write],
-            )?;
+    // Byte offset of FAT copy number `index`
+    pub fn fat_copy_offset(&self, index: u8) -> u64 {
+        self.fat_offset
+            + (index as u64)
+                * (self.boot_sector.fat_size_32 as u64)
+                * (self.boot_sector.bytes_per_sector as u64)
+    }
 
-            remaining_size -= to_write as u32;
-            data_offset += to_write;
-            cluster_offset = 0;
-            cluster_index += 1;
+    pub fn get_next_cluster(&self, cluster: u32) -> Result<u32> {
+        match self.fat.entries.get(cluster as usize) {
+            Some(value) => Ok(value & 0x0FFFFFFF),
+            None => Err(FsError::corrupted(format!("cluster {} is out of range", cluster))),
         }
+    }
 
+    // Reads `buf.len()` bytes starting `offset` bytes into `cluster`. The
+    // range must not run past the end of the cluster.
+    pub fn read_cluster_data(&mut self, cluster: u32, offset: u32, buf: &mut [u8]) -> Result<()> {
+        debug_assert!(offset as usize + buf.len() <= self.bytes_per_cluster() as usize);
+        let cluster_start = self.cluster_to_offset(cluster);
+        self.device.read_at(cluster_start + offset as u64, buf)?;
         Ok(())
     }
 
-    pub fn get_cluster_chain(
-        &self,
-        image_file: &mut File,
-        start_cluster: u32,
-    ) -> io::Result<Vec<u32>> {
+    // Writes `data` starting `offset` bytes into `cluster`. The range must
+    // not run past the end of the cluster.
+    pub fn write_cluster_data(&mut self, cluster: u32, offset: u32, data: &[u8]) -> Result<()> {
+        debug_assert!(offset as usize + data.len() <= self.bytes_per_cluster() as usize);
+        let cluster_start = self.cluster_to_offset(cluster);
+        self.device.write_at(cluster_start + offset as u64, data)?;
+        Ok(())
+    }
+
+    pub fn get_cluster_chain(&self, start_cluster: u32) -> Result<Vec<u32>> {
         let mut chain = Vec::new();
         let mut cluster = start_cluster;
 
         while cluster < 0x0FFFFFF8 && cluster != 0 {
+            if cluster < 2 || cluster > self.max_cluster() {
+                return Err(FsError::corrupted(format!(
+                    "cluster chain starting at {} links to invalid cluster {}",
+                    start_cluster, cluster
+                )));
+            }
+            // A chain longer than the data region must run in a circle
+            if chain.len() >= self.total_clusters as usize {
+                return Err(FsError::corrupted(format!(
+                    "cluster chain starting at {} loops",
+                    start_cluster
+                )));
+            }
             chain.push(cluster);
-            cluster = self.get_next_cluster(image_file, cluster)?;
+            cluster = self.get_next_cluster(cluster)?;
         }
 
         Ok(chain)
@@ -304,34 +776,104 @@ impl FAT32 {
             * self.boot_sector.sectors_per_cluster as u32
     }
 
-    pub fn allocate_cluster(&mut self, image_file: &mut File) -> io::Result<u32> {
-        // Search the FAT for a free cluster (0x00000000)
-        for cluster in 2..self.total_clusters {
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            if next_cluster == 0x00000000 {
-                // Mark cluster as end of chain
-                self.set_next_cluster(image_file, cluster, 0x0FFFFFF8)?;
-                return Ok(cluster);
+    pub fn allocate_cluster(&mut self) -> Result<u32> {
+        // Start searching at the FSInfo next free hint, wrapping around to cluster 2
+        let hint = match &self.fs_info {
+            Some(fs_info) if fs_info.next_free >= 2 && fs_info.next_free <= self.max_cluster() => {
+                fs_info.next_free
+            }
+            _ => 2,
+        };
+
+        let cluster = match self.fat.find_free(hint) {
+            Some(cluster) => cluster,
+            None => return Err(FsError::NoSpace),
+        };
+
+        // Mark cluster as end of chain
+        self.set_next_cluster(cluster, 0x0FFFFFF8)?;
+
+        let max_cluster = self.max_cluster();
+        if let Some(fs_info) = &mut self.fs_info {
+            fs_info.next_free = if cluster < max_cluster { cluster + 1 } else { 2 };
+        }
+
+        Ok(cluster)
+    }
+
+    pub fn set_next_cluster(&mut self, cluster: u32, next_cluster: u32) -> Result<()> {
+        if cluster < 2 || cluster > self.max_cluster() {
+            return Err(FsError::corrupted(format!("cluster {} is out of range", cluster)));
+        }
+
+        // Written to the FAT copies on the next flush
+        self.fat.set(cluster, next_cluster);
+        Ok(())
+    }
+
+    // FAT copies that have to be updated on every write
+    fn fat_write_targets(&self) -> Vec<u8> {
+        if self.fat_mirroring() {
+            (0..self.boot_sector.num_fats).collect()
+        } else {
+            vec![self.active_fat()]
+        }
+    }
+
+    // Returns the indices of FAT copies that differ from the active FAT.
+    // Copies are only expected to match while mirroring is enabled.
+    pub fn find_divergent_fats(&mut self) -> Result<Vec<u8>> {
+        let mut divergent = Vec::new();
+        if !self.fat_mirroring() {
+            return Ok(divergent);
+        }
+        self.flush()?;
+
+        let active = self.active_fat();
+        let sector_size = self.boot_sector.bytes_per_sector as usize;
+        let mut active_sector = vec![0u8; sector_size];
+        let mut copy_sector = vec![0u8; sector_size];
+
+        for index in 0..self.boot_sector.num_fats {
+            if index == active {
+                continue;
+            }
+            for sector in 0..self.boot_sector.fat_size_32 as u64 {
+                self.device
+                    .read_sector(self.fat_copy_sector(active) + sector, &mut active_sector)?;
+                self.device
+                    .read_sector(self.fat_copy_sector(index) + sector, &mut copy_sector)?;
+
+                if active_sector != copy_sector {
+                    divergent.push(index);
+                    break;
+                }
             }
         }
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No free clusters available.",
-        ))
+
+        Ok(divergent)
     }
 
-    pub fn set_next_cluster(
-        &mut self,
-        image_file: &mut File,
-        cluster: u32,
-        next_cluster: u32,
-    ) -> io::Result<()> {
-        let fat_offset = self.fat_offset + (cluster * 4) as u64;
-        image_file.seek(SeekFrom::Start(fat_offset))?;
-        let next_cluster_bytes = (next_cluster & 0x0FFFFFFF).to_le_bytes();
-        image_file.write_all(&next_cluster_bytes)?;
+    // Overwrites every other FAT copy with the contents of the active FAT
+    pub fn sync_fat_copies(&mut self) -> Result<()> {
+        self.flush()?;
+
+        let active = self.active_fat();
+        let sector_size = self.boot_sector.bytes_per_sector as usize;
+        let mut sector_buffer = vec![0u8; sector_size];
 
-        // Update copies of FAT if necessary (omitted for simplicity)
+        for sector in 0..self.boot_sector.fat_size_32 as u64 {
+            self.device
+                .read_sector(self.fat_copy_sector(active) + sector, &mut sector_buffer)?;
+
+            for index in 0..self.boot_sector.num_fats {
+                if index == active {
+                    continue;
+                }
+                self.device
+                    .write_sector(self.fat_copy_sector(index) + sector, &sector_buffer)?;
+            }
+        }
 
         Ok(())
     }
@@ -341,76 +883,99 @@ impl FAT32 {
     // create_directory and related helper methods
     pub fn create_directory(
         &mut self,
-        image_file: &mut File,
         parent_cluster: u32,
         dirname: &str,
-    ) -> io::Result<()> {
+    ) -> Result<()> {
         // Allocate a new cluster for the directory
-        let new_dir_cluster = self.allocate_cluster(image_file)?;
-
-        // Initialize the new directory with '.' and '..' entries
-        self.initialize_directory(image_file, new_dir_cluster, parent_cluster)?;
-
-        // Create a directory entry in the parent directory
-        self.add_directory_entry(
-            image_file,
-            parent_cluster,
-            dirname,
-            new_dir_cluster,
-            true, // is_directory
-        )?;
-
-        Ok(())
+        let new_dir_cluster = self.allocate_cluster()?;
+
+        // Initialize the new directory with '.' and '..' entries, then create
+        // a directory entry in the parent directory
+        let result = self
+            .initialize_directory(new_dir_cluster, parent_cluster)
+            .and_then(|()| {
+                self.add_directory_entry(
+                    parent_cluster,
+                    dirname,
+                    new_dir_cluster,
+                    true, // is_directory
+                )
+            });
+        if result.is_err() {
+            // Don't leak the cluster, e.g. when the parent is full
+            let _ = self.free_cluster_chain(new_dir_cluster);
+        }
+        result
     }
 
     fn initialize_directory(
-        &self,
-        image_file: &mut File,
+        &mut self,
         dir_cluster: u32,
         parent_cluster: u32,
-    ) -> io::Result<()> {
+    ) -> Result<()> {
         let dir_offset = self.cluster_to_offset(dir_cluster);
-        image_file.seek(SeekFrom::Start(dir_offset))?;
+        let mut cluster_buffer = vec![0u8; self.bytes_per_cluster() as usize];
 
         // Create '.' entry
         let dot_entry = self.create_directory_entry(
-            ".",
+            DOT_NAME,
             0x10, // attr: Directory attribute
             dir_cluster,
             0, // file_size
         );
-        image_file.write_all(&dot_entry)?;
+        cluster_buffer[0..32].copy_from_slice(&dot_entry);
 
         // Create '..' entry
         let dotdot_entry = self.create_directory_entry(
-            "..",
+            DOTDOT_NAME,
             0x10,
-            parent_cluster,
+            self.dotdot_cluster(parent_cluster),
             0,
         );
-        image_file.write_all(&dotdot_entry)?;
+        cluster_buffer[32..64].copy_from_slice(&dotdot_entry);
 
-        // Fill the rest of the cluster with zeros
-        let remaining_size = self.bytes_per_cluster() - 64; // 64 bytes for two entries
-        let zero_buffer = vec![0u8; remaining_size as usize];
-        image_file.write_all(&zero_buffer)?;
+        // The rest of the cluster stays zeroed
+        self.device.write_at(dir_offset, &cluster_buffer)?;
 
         Ok(())
     }
 
+    // Cluster number stored in a '..' entry: 0 stands for the root directory
+    fn dotdot_cluster(&self, parent_cluster: u32) -> u32 {
+        if parent_cluster == self.boot_sector.root_cluster {
+            0
+        } else {
+            parent_cluster
+        }
+    }
+
+    // Points the '..' entry of the directory at `dir_cluster` at a new parent
+    pub fn set_parent_directory(
+        &mut self,
+        dir_cluster: u32,
+        parent_cluster: u32,
+    ) -> Result<()> {
+        let dotdot = self.find_entry(dir_cluster, "..")?.ok_or_else(|| {
+            FsError::corrupted(format!(
+                "directory at cluster {} has no '..' entry",
+                dir_cluster
+            ))
+        })?;
+        let cluster = self.dotdot_cluster(parent_cluster);
+        self.update_entry(dotdot.offset, cluster, 0)
+    }
+
     // create_file method
     pub fn create_file(
         &mut self,
-        image_file: &mut File,
         parent_cluster: u32,
         filename: &str,
-    ) -> io::Result<()> {
+    ) -> Result<()> {
         // No need to allocate a cluster for an empty file
         let first_cluster = 0;
 
         // Create a file entry in the parent directory
         self.add_directory_entry(
-            image_file,
             parent_cluster,
             filename,
             first_cluster,
@@ -423,73 +988,130 @@ impl FAT32 {
     // Helper method to add a directory entry
     fn add_directory_entry(
         &mut self,
-        image_file: &mut File,
         dir_cluster: u32,
         name: &str,
         first_cluster: u32,
         is_directory: bool,
-    ) -> io::Result<()> {
-        let mut cluster = dir_cluster;
+    ) -> Result<()> {
+        let attr = if is_directory { 0x10 } else { 0x20 };
+        let entry = self.create_directory_entry(
+            &[0x20; 11], // filled in by write_named_entry
+            attr,
+            first_cluster,
+            0, // file_size
+        );
+        self.write_named_entry(dir_cluster, name, entry)
+    }
+
+    // Helper method to store a short entry under `name`, preceded by long
+    // name entries when the name can't be represented as 8.3
+    fn write_named_entry(
+        &mut self,
+        dir_cluster: u32,
+        name: &str,
+        mut entry: [u8; 32],
+    ) -> Result<()> {
+        if !is_valid_long_name(name) {
+            return Err(FsError::InvalidName(name.to_string()));
+        }
+
+        let (short_name, needs_long_name) =
+            self.generate_short_name(dir_cluster, name)?;
+        entry[0..11].copy_from_slice(&short_name);
+
+        let mut run = if needs_long_name {
+            build_long_name_entries(name, lfn_checksum(&short_name))
+        } else {
+            Vec::new()
+        };
+        run.push(entry);
+
+        let slots = self.find_free_entry_slots(dir_cluster, run.len())?;
+        for (slot, raw) in slots.iter().zip(&run) {
+            self.device.write_at(*slot, raw)?;
+        }
+
+        Ok(())
+    }
+
+    // Helper method to find `count` consecutive free entry slots in a
+    // directory, extending its cluster chain if there is no such run
+    fn find_free_entry_slots(
+        &mut self,
+        dir_cluster: u32,
+        count: usize,
+    ) -> Result<Vec<u64>> {
+        let mut cluster_chain = self.get_cluster_chain(dir_cluster)?;
+        let cluster_size = self.bytes_per_cluster() as u64;
+        let mut run = Vec::with_capacity(count);
+        let mut index = 0;
 
         loop {
-            let cluster_offset = self.cluster_to_offset(cluster);
+            if index == cluster_chain.len() {
+                // Every directory has at least one cluster to link the new one to
+                if index == 0 {
+                    check_directory_cluster(dir_cluster)?;
+                    return Err(FsError::corrupted(format!(
+                        "directory at cluster {} has no clusters",
+                        dir_cluster
+                    )));
+                }
+                // End of cluster chain, need to allocate a new cluster
+                let new_cluster = self.allocate_cluster()?;
+                self.set_next_cluster(cluster_chain[index - 1], new_cluster)?;
+                cluster_chain.push(new_cluster);
 
-            let cluster_size = self.bytes_per_cluster();
+                // Initialize the new cluster with zeros
+                let new_cluster_offset = self.cluster_to_offset(new_cluster);
+                let zero_buffer = vec![0u8; cluster_size as usize];
+                self.device.write_at(new_cluster_offset, &zero_buffer)?;
+            }
+
+            let cluster_offset = self.cluster_to_offset(cluster_chain[index]);
             let mut offset = cluster_offset;
-            while offset < cluster_offset + cluster_size as u64 {
-                image_file.seek(SeekFrom::Start(offset))?;
-                let mut buffer = [0u8; 32];
-                image_file.read_exact(&mut buffer)?;
+            while offset < cluster_offset + cluster_size {
+                let mut buffer = [0u8; 1];
+                self.device.read_at(offset, &mut buffer)?;
 
                 if buffer[0] == 0x00 || buffer[0] == 0xE5 {
-                    // Found an empty or deleted entry, can use this slot
-                    image_file.seek(SeekFrom::Start(offset))?;
-                    let attr = if is_directory { 0x10 } else { 0x20 };
-                    let entry = self.create_directory_entry(
-                        name,
-                        attr,
-                        first_cluster,
-                        0, // file_size
-                    );
-                    image_file.write_all(&entry)?;
-                    return Ok(());
+                    // Found an empty or deleted entry, extend the current run
+                    run.push(offset);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
                 }
 
                 offset += 32;
             }
 
             // Need to move to the next cluster in the directory
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            if next_cluster >= 0x0FFFFFF8 {
-                // End of cluster chain, need to allocate a new cluster
-                let new_cluster = self.allocate_cluster(image_file)?;
-                self.set_next_cluster(image_file, cluster, new_cluster)?;
-                cluster = new_cluster;
-
-                // Initialize the new cluster with zeros
-                let new_cluster_offset = self.cluster_to_offset(new_cluster);
-                image_file.seek(SeekFrom::Start(new_cluster_offset))?;
-                let zero_buffer = vec![0u8; self.bytes_per_cluster() as usize];
-                image_file.write_all(&zero_buffer)?;
-            } else {
-                cluster = next_cluster;
-            }
+            index += 1;
         }
     }
 
     // Helper method to create a directory entry
     fn create_directory_entry(
         &self,
-        name: &str,
+        short_name: &[u8; 11],
         attr: u8,
         first_cluster: u32,
         file_size: u32,
     ) -> [u8; 32] {
         let mut entry = [0u8; 32];
-        let name_bytes = self.format_filename(name);
-        entry[0..11].copy_from_slice(&name_bytes);
+        entry[0..11].copy_from_slice(short_name);
         entry[11] = attr;
 
+        // Creation, last access and last write all happen now
+        let now = Timestamp::now();
+        entry[13] = now.fat_time_tenths();
+        entry[14..16].copy_from_slice(&now.fat_time().to_le_bytes());
+        entry[16..18].copy_from_slice(&now.fat_date().to_le_bytes());
+        entry[18..20].copy_from_slice(&now.fat_date().to_le_bytes());
+        entry[22..24].copy_from_slice(&now.fat_time().to_le_bytes());
+        entry[24..26].copy_from_slice(&now.fat_date().to_le_bytes());
+
         // First cluster high (bits 16-31)
         entry[20..22].copy_from_slice(&( ( (first_cluster >> 16) as u16 ).to_le_bytes() ));
         // First cluster low (bits 0-15)
@@ -500,275 +1122,763 @@ impl FAT32 {
         entry
     }
 
-    // Helper method to format the filename
-    fn format_filename(&self, name: &str) -> [u8; 11] {
-        let mut name_bytes = [0x20u8; 11]; // Fill with spaces
-        let name = name.to_uppercase();
-
-        let (name_part, ext_part) = if let Some(pos) = name.find('.') {
-            (&name[..pos], &name[pos + 1..])
+    // Helper method to pick the short name stored for `name`. Returns the
+    // 11-byte name and whether long name entries are needed alongside it.
+    fn generate_short_name(
+        &mut self,
+        dir_cluster: u32,
+        name: &str,
+    ) -> Result<([u8; 11], bool)> {
+        let (base, ext, lossy) = short_name_basis(name);
+        let taken: Vec<String> = self
+            .read_directory_entries(dir_cluster)?
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+
+        let display = if ext.is_empty() {
+            base.clone()
         } else {
-            (name.as_str(), "")
+            format!("{}.{}", base, ext)
         };
+        if !lossy && !taken.contains(&display) {
+            // Fits 8.3 as is; only mixed or lower case names need a long name
+            return Ok((pack_short_name(&base, &ext), display != name));
+        }
 
-        let name_part = &name_part[..std::cmp::min(8, name_part.len())];
-        let ext_part = &ext_part[..std::cmp::min(3, ext_part.len())];
-
-        name_bytes[..name_part.len()].copy_from_slice(name_part.as_bytes());
-        name_bytes[8..8 + ext_part.len()].copy_from_slice(ext_part.as_bytes());
+        // Generate a "BASE~N" alias that isn't used in this directory yet
+        for n in 1..1_000_000u32 {
+            let tail = format!("~{}", n);
+            let keep = std::cmp::min(base.len(), 8 - tail.len());
+            let alias_base = format!("{}{}", &base[..keep], tail);
+            let short_name = pack_short_name(&alias_base, &ext);
+            if !taken.contains(&format_short_name(&short_name)) {
+                return Ok((short_name, true));
+            }
+        }
 
-        name_bytes
+        Err(FsError::AlreadyExists(name.to_string()))
     }
 
     // update_entry_name method
     pub fn update_entry_name(
         &mut self,
-        image_file: &mut File,
         dir_cluster: u32,
         entry: &DirectoryEntry,
         new_name: &str,
-    ) -> io::Result<()> {
-        let mut cluster = dir_cluster;
+    ) -> Result<()> {
+        if !is_valid_long_name(new_name) {
+            return Err(FsError::InvalidName(new_name.to_string()));
+        }
 
-        loop {
-            let cluster_offset = self.cluster_to_offset(cluster);
+        // Save the old run, long name entries first, so it can be put back
+        let mut old_run = Vec::new();
+        for &offset in entry.lfn_offsets.iter().chain([&entry.offset]) {
+            let mut raw = [0u8; 32];
+            self.device.read_at(offset, &mut raw)?;
+            old_run.push((offset, raw));
+        }
+        // Keep everything but the name from the old short entry
+        let raw = old_run[old_run.len() - 1].1;
+
+        // The old entries are freed first so their slots can be reused
+        self.remove_directory_entry(entry)?;
+        let result = self.write_named_entry(dir_cluster, new_name, raw);
+        if result.is_err() {
+            // A failed rename, e.g. on a full volume, leaves the old name
+            for (offset, raw) in &old_run {
+                self.device.write_at(*offset, raw)?;
+            }
+        }
+        result
+    }
 
-            let cluster_size = self.bytes_per_cluster();
-            let mut offset = cluster_offset;
-            while offset < cluster_offset + cluster_size as u64 {
-                image_file.seek(SeekFrom::Start(offset))?;
-                let mut buffer = [0u8; 32];
-                image_file.read_exact(&mut buffer)?;
+    // remove_directory_entry method
+    pub fn remove_directory_entry(
+        &mut self,
+        entry: &DirectoryEntry,
+    ) -> Result<()> {
+        self.remove_long_name_entries(entry)?;
 
-                if buffer[0] == 0x00 {
-                    // No more entries
-                    return Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        "Directory entry not found.",
-                    ));
-                }
+        // Mark the short entry as deleted
+        self.device.write_at(entry.offset, &[0xE5])?;
+        Ok(())
+    }
 
-                if buffer[0] == 0xE5 {
-                    // Deleted entry, skip
-                    offset += 32;
-                    continue;
-                }
+    // Helper method to mark the long name entries of an entry as deleted
+    fn remove_long_name_entries(
+        &mut self,
+        entry: &DirectoryEntry,
+    ) -> Result<()> {
+        for &lfn_offset in &entry.lfn_offsets {
+            self.device.write_at(lfn_offset, &[0xE5])?;
+        }
+        Ok(())
+    }
 
-                let attr = buffer[11];
-                if attr == 0x0F {
-                    // Long file name entry, skip
-                    offset += 32;
-                    continue;
-                }
+    // free_cluster_chain method
+    pub fn free_cluster_chain(&mut self, start_cluster: u32) -> Result<()> {
+        let mut cluster = start_cluster;
 
-                let name = String::from_utf8_lossy(&buffer[0..11])
-                    .trim()
-                    .to_string();
+        while cluster < 0x0FFFFFF8 && cluster != 0 {
+            let next_cluster = self.get_next_cluster(cluster)?;
+            // Mark the cluster as free (0x00000000)
+            self.set_next_cluster(cluster, 0x00000000)?;
+            cluster = next_cluster;
+        }
 
-                if name == entry.name {
-                    // Found the entry, update the name
-                    let name_bytes = self.format_filename(new_name);
-                    image_file.seek(SeekFrom::Start(offset))?;
-                    image_file.write_all(&name_bytes)?;
-                    return Ok(());
-                }
+        Ok(())
+    }
 
-                offset += 32;
-            }
+    // update_entry method: rewrites the first cluster and size of the short
+    // entry at `entry_offset`
+    pub fn update_entry(
+        &mut self,
+        entry_offset: u64,
+        first_cluster: u32,
+        file_size: u32,
+    ) -> Result<()> {
+        let mut buffer = [0u8; 32];
+        self.device.read_at(entry_offset, &mut buffer)?;
 
-            // Move to the next cluster in the directory
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            if next_cluster >= 0x0FFFFFF8 {
-                // End of cluster chain, entry not found
-                break;
-            } else {
-                cluster = next_cluster;
-            }
-        }
+        // First cluster high (bits 16-31)
+        buffer[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        // First cluster low (bits 0-15)
+        buffer[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        // File size
+        buffer[28..32].copy_from_slice(&file_size.to_le_bytes());
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Directory entry not found.",
-        ))
+        self.device.write_at(entry_offset, &buffer)?;
+        Ok(())
     }
 
-    // remove_directory_entry method
-    pub fn remove_directory_entry(
+    // Rewrites the attribute byte of the short entry at `entry_offset`
+    pub fn set_entry_attr(&mut self, entry_offset: u64, attr: u8) -> Result<()> {
+        self.device.write_at(entry_offset + 11, &[attr])?;
+        Ok(())
+    }
+
+    // Rewrites the timestamps of the short entry at `entry_offset`; a None
+    // leaves that timestamp as it is. Only the date of the last access is
+    // stored.
+    pub fn set_entry_times(
         &mut self,
-        image_file: &mut File,
-        dir_cluster: u32,
-        entry: &DirectoryEntry,
-    ) -> io::Result<()> {
-        let mut cluster = dir_cluster;
+        entry_offset: u64,
+        created: Option<Timestamp>,
+        modified: Option<Timestamp>,
+        accessed: Option<Timestamp>,
+    ) -> Result<()> {
+        let mut buffer = [0u8; 32];
+        self.device.read_at(entry_offset, &mut buffer)?;
+
+        if let Some(created) = created {
+            buffer[13] = created.fat_time_tenths();
+            buffer[14..16].copy_from_slice(&created.fat_time().to_le_bytes());
+            buffer[16..18].copy_from_slice(&created.fat_date().to_le_bytes());
+        }
+        if let Some(accessed) = accessed {
+            buffer[18..20].copy_from_slice(&accessed.fat_date().to_le_bytes());
+        }
+        if let Some(modified) = modified {
+            buffer[22..24].copy_from_slice(&modified.fat_time().to_le_bytes());
+            buffer[24..26].copy_from_slice(&modified.fat_date().to_le_bytes());
+        }
 
-        loop {
-            let cluster_offset = self.cluster_to_offset(cluster);
+        self.device.write_at(entry_offset, &buffer)?;
+        Ok(())
+    }
+}
 
-            let cluster_size = self.bytes_per_cluster();
-            let mut offset = cluster_offset;
-            while offset < cluster_offset + cluster_size as u64 {
-                image_file.seek(SeekFrom::Start(offset))?;
-                let mut buffer = [0u8; 32];
-                image_file.read_exact(&mut buffer)?;
+impl<D: BlockDevice> Drop for FAT32<D> {
+    fn drop(&mut self) {
+        // Errors can't be reported here; call flush to see them
+        let _ = self.flush();
+    }
+}
 
-                if buffer[0] == 0x00 {
-                    // No more entries
-                    return Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        "Directory entry not found.",
-                    ));
-                }
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    // 8.3 name in its displayed form, e.g. `README.TXT`
+    pub name: String,
+    // VFAT long name, if a valid one precedes the short entry
+    pub long_name: Option<String>,
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub file_size: u32,
+    // None when the entry's date field was never set
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    // Only the date of the last access is stored; the time is midnight
+    pub accessed: Option<Timestamp>,
+    // Byte offset of the short entry in the image
+    pub offset: u64,
+    // Byte offsets of the entry's long name entries
+    pub lfn_offsets: Vec<u64>,
+}
 
-                if buffer[0] == 0xE5 {
-                    // Deleted entry, skip
-                    offset += 32;
-                    continue;
-                }
+impl DirectoryEntry {
+    // Name to show to the user: the long name when there is one
+    pub fn display_name(&self) -> &str {
+        self.long_name.as_deref().unwrap_or(&self.name)
+    }
 
-                let attr = buffer[11];
-                if attr == 0x0F {
-                    // Long file name entry, skip
-                    offset += 32;
-                    continue;
-                }
+    // Whether `name` is this entry's long or short name, ignoring case
+    pub fn matches(&self, name: &str) -> bool {
+        if self.name.eq_ignore_ascii_case(name) {
+            return true;
+        }
+        match &self.long_name {
+            Some(long_name) => long_name.to_uppercase() == name.to_uppercase(),
+            None => false,
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attr & 0x10 != 0
+    }
 
-                let name = String::from_utf8_lossy(&buffer[0..11])
-                    .trim()
-                    .to_string();
+    pub fn is_volume_label(&self) -> bool {
+        self.attr & 0x08 != 0
+    }
 
-                if name == entry.name {
-                    // Found the entry, mark it as deleted
-                    image_file.seek(SeekFrom::Start(offset))?;
-                    image_file.write_all(&[0xE5])?;
-                    return Ok(());
+    pub fn is_read_only(&self) -> bool {
+        self.attr & 0x01 != 0
+    }
+
+    // Hidden or system entries, left out of plain listings
+    pub fn is_hidden(&self) -> bool {
+        self.attr & 0x06 != 0
+    }
+
+    // Attribute bits as `RHSVDA`, with `-` for each bit that is clear
+    pub fn attr_flags(&self) -> String {
+        b"RHSVDA"
+            .iter()
+            .enumerate()
+            .map(|(bit, &flag)| {
+                if self.attr & (1 << bit) != 0 {
+                    flag as char
+                } else {
+                    '-'
                 }
+            })
+            .collect()
+    }
 
-                offset += 32;
+    pub fn is_file(&self) -> bool {
+        !self.is_directory()
+    }
+}
+
+// In-memory copy of the active FAT with a bitmap of free clusters.
+// Entries keep their reserved top 4 bits so they can be written back as is.
+#[derive(Default)]
+struct FatCache {
+    entries: Vec<u32>,
+    free_map: Vec<u64>,
+    free_count: u32,
+    // Inclusive range of entries changed since the last flush
+    dirty: Option<(u32, u32)>,
+}
+
+impl FatCache {
+    fn new(entries: Vec<u32>) -> Self {
+        let mut cache = FatCache {
+            free_map: vec![0; entries.len().div_ceil(64)],
+            entries,
+            free_count: 0,
+            dirty: None,
+        };
+        for cluster in 2..cache.entries.len() {
+            if cache.entries[cluster] & 0x0FFFFFFF == 0 {
+                cache.free_map[cluster / 64] |= 1 << (cluster % 64);
+                cache.free_count += 1;
             }
+        }
+        cache
+    }
 
-            // Move to the next cluster in the directory
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            if next_cluster >= 0x0FFFFFF8 {
-                // End of cluster chain, entry not found
-                break;
-            } else {
-                cluster = next_cluster;
+    fn set(&mut self, cluster: u32, next_cluster: u32) {
+        let index = cluster as usize;
+        let old = self.entries[index];
+        let new = (old & 0xF0000000) | (next_cluster & 0x0FFFFFFF);
+        self.entries[index] = new;
+
+        let (was_free, is_free) = (old & 0x0FFFFFFF == 0, new & 0x0FFFFFFF == 0);
+        if was_free && !is_free {
+            self.free_map[index / 64] &= !(1 << (index % 64));
+            self.free_count -= 1;
+        } else if !was_free && is_free {
+            self.free_map[index / 64] |= 1 << (index % 64);
+            self.free_count += 1;
+        }
+
+        self.dirty = Some(match self.dirty {
+            Some((first, last)) => (first.min(cluster), last.max(cluster)),
+            None => (cluster, cluster),
+        });
+    }
+
+    // First free cluster at or after `start`, wrapping around to cluster 2
+    fn find_free(&self, start: u32) -> Option<u32> {
+        let words = self.free_map.len();
+        let start = start as usize;
+        for step in 0..=words {
+            let word_index = (start / 64 + step) % words;
+            let mut word = self.free_map[word_index];
+            if step == 0 {
+                // Ignore clusters before the start in the first word
+                word &= !0u64 << (start % 64);
+            }
+            if word != 0 {
+                return Some((word_index * 64) as u32 + word.trailing_zeros());
             }
         }
+        None
+    }
+}
+
+// Collects the long name entries preceding a short entry. Entries are
+// stored last-part-first, each carrying its ordinal and the checksum of
+// the short name they belong to.
+struct LongNameBuilder {
+    parts: Vec<[u16; 13]>,
+    offsets: Vec<u64>,
+    checksum: u8,
+    next_ordinal: u8,
+    valid: bool,
+}
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Directory entry not found.",
-        ))
+impl LongNameBuilder {
+    fn new() -> Self {
+        LongNameBuilder {
+            parts: Vec::new(),
+            offsets: Vec::new(),
+            checksum: 0,
+            next_ordinal: 0,
+            valid: false,
+        }
     }
 
-    // free_cluster_chain method
-    pub fn free_cluster_chain(
-        &mut self,
-        image_file: &mut File,
-        start_cluster: u32,
-    ) -> io::Result<()> {
-        let mut cluster = start_cluster;
+    fn reset(&mut self) {
+        self.parts.clear();
+        self.offsets.clear();
+        self.valid = false;
+    }
 
-        while cluster < 0x0FFFFFF8 && cluster != 0 {
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            // Mark the cluster as free (0x00000000)
-            self.set_next_cluster(image_file, cluster, 0x00000000)?;
-            cluster = next_cluster;
+    fn push(&mut self, buffer: &[u8; 32], offset: u64) {
+        let ordinal = buffer[0] & 0x3F;
+        let checksum = buffer[13];
+
+        if buffer[0] & 0x40 != 0 {
+            // Last logical entry, which comes first on disk: start a new name
+            self.reset();
+            self.valid = (1..=20).contains(&ordinal);
+            self.checksum = checksum;
+            self.next_ordinal = ordinal;
+        } else if !self.valid || ordinal != self.next_ordinal || checksum != self.checksum {
+            // Out of sequence, so the whole run is an orphan
+            self.reset();
+            return;
         }
 
-        Ok(())
+        let mut part = [0u16; 13];
+        for (i, chunk) in buffer[1..11]
+            .chunks(2)
+            .chain(buffer[14..26].chunks(2))
+            .chain(buffer[28..32].chunks(2))
+            .enumerate()
+        {
+            part[i] = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        self.parts.push(part);
+        self.offsets.push(offset);
+        self.next_ordinal = ordinal.wrapping_sub(1);
     }
 
-    // update_file_size method
-    pub fn update_file_size(
-        &mut self,
-        image_file: &mut File,
-        first_cluster: u32,
-        dir_cluster: u32,
-        new_size: u32,
-    ) -> io::Result<()> {
-        let mut cluster = dir_cluster;
+    // Returns the long name for the short entry `short_name` if the collected
+    // run is complete and its checksum matches, along with the offsets of the
+    // entries that were consumed
+    fn finish(&mut self, short_name: &[u8; 11]) -> (Option<String>, Vec<u64>) {
+        let complete = self.valid
+            && self.next_ordinal == 0
+            && self.checksum == lfn_checksum(short_name);
+        if !complete {
+            self.reset();
+            return (None, Vec::new());
+        }
 
-        loop {
-            let cluster_offset = self.cluster_to_offset(cluster);
+        // Parts were collected in reverse order
+        let units: Vec<u16> = self
+            .parts
+            .iter()
+            .rev()
+            .flatten()
+            .copied()
+            .take_while(|&unit| unit != 0x0000)
+            .collect();
+        let long_name = char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        let offsets = std::mem::take(&mut self.offsets);
+        self.reset();
+        (Some(long_name), offsets)
+    }
+}
 
-            let cluster_size = self.bytes_per_cluster();
-            let mut offset = cluster_offset;
-            while offset < cluster_offset + cluster_size as u64 {
-                image_file.seek(SeekFrom::Start(offset))?;
-                let mut buffer = [0u8; 32];
-                image_file.read_exact(&mut buffer)?;
+// Checksum of an 11-byte short name, stored in each of its long name entries
+pub fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| (sum >> 1 | (sum & 1) << 7).wrapping_add(b))
+}
 
-                if buffer[0] == 0x00 {
-                    // No more entries
-                    return Err(io::Error::new(
-                        io::ErrorKind::NotFound,
-                        "File entry not found.",
-                    ));
-                }
+// Short names of the '.' and '..' entries
+const DOT_NAME: &[u8; 11] = b".          ";
+const DOTDOT_NAME: &[u8; 11] = b"..         ";
+
+// Characters other than letters and digits allowed in a short name
+pub(crate) const SHORT_NAME_SPECIAL: &str = "$%'-_@~`!(){}^#&";
+
+// Directories always have a cluster; 0 and 1 in a directory entry mean
+// the entry is damaged
+fn check_directory_cluster(cluster: u32) -> Result<u32> {
+    if cluster < 2 {
+        return Err(FsError::corrupted(format!(
+            "directory entry points at cluster {}",
+            cluster
+        )));
+    }
+    Ok(cluster)
+}
 
-                if buffer[0] == 0xE5 {
-                    // Deleted entry, skip
-                    offset += 32;
-                    continue;
-                }
+// Checks that the boot sector describes a FAT32 volume that fits in an image
+// of `image_size` bytes, so the layout can be computed without overflow.
+// Returns the number of clusters in the data region, or why it can't be used.
+fn validate_boot_sector(bs: &BootSector, image_size: u64) -> std::result::Result<u32, String> {
+    if bs.signature != 0xAA55 {
+        return Err(format!("boot sector signature is {:#06X}, not 0xAA55", bs.signature));
+    }
+    if !bs.bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bs.bytes_per_sector) {
+        return Err(format!(
+            "{} bytes per sector; it must be a power of two from 512 to 4096",
+            bs.bytes_per_sector
+        ));
+    }
+    if !bs.sectors_per_cluster.is_power_of_two() {
+        return Err(format!(
+            "{} sectors per cluster; it must be a power of two from 1 to 128",
+            bs.sectors_per_cluster
+        ));
+    }
+    if bs.reserved_sector_count == 0 {
+        return Err("there are no reserved sectors".to_string());
+    }
+    if bs.num_fats == 0 {
+        return Err("there are no FATs".to_string());
+    }
+    if bs.fat_size_16 != 0 {
+        return Err("the 16-bit FAT size is set, so this is FAT12 or FAT16".to_string());
+    }
+    if bs.fat_size_32 == 0 {
+        return Err("the FAT size is 0".to_string());
+    }
 
-                let attr = buffer[11];
-                if attr == 0x0F {
-                    // Long file name entry, skip
-                    offset += 32;
-                    continue;
-                }
+    let metadata_sectors =
+        bs.reserved_sector_count as u64 + bs.num_fats as u64 * bs.fat_size_32 as u64;
+    if bs.total_sectors as u64 <= metadata_sectors {
+        return Err(format!(
+            "{} sectors leave no room for data after {} sectors of reserved area and FATs",
+            bs.total_sectors, metadata_sectors
+        ));
+    }
+    let total_clusters =
+        (bs.total_sectors as u64 - metadata_sectors) / bs.sectors_per_cluster as u64;
+    if total_clusters < 65525 {
+        return Err(format!(
+            "{} clusters is too few for FAT32 (at least 65525), so this is FAT12 or FAT16",
+            total_clusters
+        ));
+    }
+    if total_clusters > 0x0FFFFFF5 {
+        return Err(format!(
+            "{} clusters is too many for FAT32 (at most 268435445)",
+            total_clusters
+        ));
+    }
+    let max_cluster = total_clusters as u32 + 1;
+    if bs.root_cluster < 2 || bs.root_cluster > max_cluster {
+        return Err(format!(
+            "root cluster {} is outside the data region (2 to {})",
+            bs.root_cluster, max_cluster
+        ));
+    }
 
-                let entry_first_cluster_high =
-                    u16::from_le_bytes([buffer[20], buffer[21]]) as u32;
-                let entry_first_cluster_low =
-                    u16::from_le_bytes([buffer[26], buffer[27]]) as u32;
-                let entry_first_cluster =
-                    (entry_first_cluster_high << 16) | entry_first_cluster_low;
-
-                if entry_first_cluster == first_cluster {
-                    // Found the file entry, update the file size
-                    image_file.seek(SeekFrom::Start(offset + 28))?;
-                    image_file.write_all(&new_size.to_le_bytes())?;
-                    return Ok(());
-                }
+    let volume_size = bs.total_sectors as u64 * bs.bytes_per_sector as u64;
+    if image_size < volume_size {
+        return Err(format!(
+            "the image is {} bytes, but the boot sector describes {} bytes",
+            image_size, volume_size
+        ));
+    }
 
-                offset += 32;
-            }
+    Ok(total_clusters as u32)
+}
+
+// Upper-cased, space-padded label; "NO NAME" when there is none
+pub(crate) fn volume_label_bytes(label: Option<&str>) -> Result<[u8; 11]> {
+    let label = label.unwrap_or("NO NAME").to_ascii_uppercase();
+    let valid = label.len() <= 11
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || SHORT_NAME_SPECIAL.contains(c));
+    if !valid {
+        return Err(FsError::InvalidArgument(format!(
+            "Invalid volume label '{}'.",
+            label
+        )));
+    }
+    let mut bytes = [0x20u8; 11];
+    bytes[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(bytes)
+}
+
+// Whether `name` can be stored as a long file name
+pub fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= 255
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && !name
+            .chars()
+            .any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+}
+
+// Splits `name` into the upper-cased base and extension of its short name.
+// The flag is set when characters had to be dropped or replaced.
+fn short_name_basis(name: &str) -> (String, String, bool) {
+    let upper = name.to_uppercase();
+    let mut lossy = false;
+
+    // Spaces and leading periods are not kept in short names
+    let stripped: String = upper.trim_start_matches('.').replace(' ', "");
+    if stripped.len() != upper.len() {
+        lossy = true;
+    }
 
-            // Move to the next cluster in the directory
-            let next_cluster = self.get_next_cluster(image_file, cluster)?;
-            if next_cluster >= 0x0FFFFFF8 {
-                // End of cluster chain, entry not found
+    let (base_part, ext_part) = match stripped.rfind('.') {
+        Some(pos) => (&stripped[..pos], &stripped[pos + 1..]),
+        None => (stripped.as_str(), ""),
+    };
+
+    let mut convert = |part: &str, max_len: usize| {
+        let mut out = String::new();
+        for c in part.chars() {
+            if c == '.' {
+                // Only the last period separates the extension
+                lossy = true;
+                continue;
+            }
+            if out.len() == max_len {
+                lossy = true;
                 break;
+            }
+            if c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(c) {
+                out.push(c);
             } else {
-                cluster = next_cluster;
+                out.push('_');
+                lossy = true;
             }
         }
+        out
+    };
+    let base = convert(base_part, 8);
+    let ext = convert(ext_part, 3);
+
+    (base, ext, lossy)
+}
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "File entry not found.",
-        ))
+// Splits `path` at its last '/' into the directory part and the last
+// component. Trailing slashes are ignored; a path made only of slashes is
+// the root directory, with an empty last component.
+pub fn split_path(path: &str) -> (&str, &str) {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() && !path.is_empty() {
+        return ("/", "");
+    }
+    match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(pos) => (&trimmed[..pos], &trimmed[pos + 1..]),
+        None => ("", trimmed),
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct DirectoryEntry {
-    pub name: String,
-    pub attr: u8,
-    pub first_cluster: u32,
-    pub file_size: u32,
+// Packs a base and extension into the space-padded 11-byte form
+fn pack_short_name(base: &str, ext: &str) -> [u8; 11] {
+    let mut short_name = [0x20u8; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short_name
 }
 
-impl DirectoryEntry {
-    pub fn is_directory(&self) -> bool {
-        self.attr & 0x10 != 0
+// Builds the long name entries for `name` in on-disk order (last part first)
+fn build_long_name_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(13) {
+        // Terminate the name, then pad the last part with 0xFFFF
+        units.push(0x0000);
+        while !units.len().is_multiple_of(13) {
+            units.push(0xFFFF);
+        }
     }
 
-    pub fn is_file(&self) -> bool {
-        !self.is_directory()
+    let count = units.len() / 13;
+    let mut entries = Vec::with_capacity(count);
+    for ordinal in (1..=count).rev() {
+        let part = &units[(ordinal - 1) * 13..ordinal * 13];
+        let mut entry = [0u8; 32];
+        entry[0] = ordinal as u8;
+        if ordinal == count {
+            entry[0] |= 0x40;
+        }
+        entry[11] = 0x0F;
+        entry[13] = checksum;
+
+        let positions = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (unit, pos) in part.iter().zip(positions) {
+            entry[pos..pos + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+// Turns a raw 11-byte short name into its displayed form, e.g. `README.TXT`
+fn format_short_name(short_name: &[u8; 11]) -> String {
+    let mut raw = *short_name;
+    // 0x05 stands in for a leading 0xE5 byte
+    if raw[0] == 0x05 {
+        raw[0] = 0xE5;
+    }
+    let base = String::from_utf8_lossy(&raw[0..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&raw[8..11]).trim_end().to_string();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mkfs::{self, MkfsOptions};
+
+    fn new_image() -> Vec<u8> {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        mkfs::format(&mut image, &MkfsOptions::default()).unwrap();
+        image
+    }
+
+    fn mount_error(image: Vec<u8>) -> String {
+        match FAT32::new(image) {
+            Err(FsError::NotFat32(reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("mounted a damaged image"),
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_rejected() {
+        let mut image = new_image();
+        image.truncate(20 * 1024 * 1024);
+        assert_eq!(
+            mount_error(image),
+            "the image is 20971520 bytes, but the boot sector describes 41943040 bytes"
+        );
+        assert_eq!(mount_error(vec![0u8; 100]), "the image is only 100 bytes long");
+    }
+
+    #[test]
+    fn bad_bytes_per_sector_is_rejected() {
+        let mut image = new_image();
+        for sector in [0, 6] {
+            image[sector * 512 + 11..sector * 512 + 13].copy_from_slice(&513u16.to_le_bytes());
+        }
+        assert_eq!(
+            mount_error(image),
+            "513 bytes per sector; it must be a power of two from 512 to 4096"
+        );
+    }
+
+    #[test]
+    fn damaged_boot_sector_falls_back_to_the_backup() {
+        let mut image = new_image();
+        image[11..13].copy_from_slice(&513u16.to_le_bytes());
+
+        let mut fat32 = FAT32::new(&mut image).unwrap();
+        assert_eq!(
+            fat32.mount_warnings,
+            ["The boot sector is damaged (513 bytes per sector; it must be a power of two \
+              from 512 to 4096); using the backup boot sector at sector 6. Run 'bootsector \
+              restore' to repair it."]
+        );
+        assert_eq!(fat32.boot_sector.bytes_per_sector, 512);
+        assert!(fat32.boot_sector_problem(0).unwrap().is_some());
+        assert!(fat32.update_backup_boot_sector().is_err());
+
+        assert_eq!(fat32.restore_boot_sector().unwrap(), 6);
+        assert_eq!(fat32.boot_sector_problem(0).unwrap(), None);
+        drop(fat32);
+
+        assert!(FAT32::new(&mut image).unwrap().mount_warnings.is_empty());
+        assert_eq!(image[0..512], image[6 * 512..7 * 512]);
+    }
+
+    #[test]
+    fn differing_backup_is_reported() {
+        let mut image = new_image();
+        image[6 * 512 + 3] = b'X';
+
+        let mut fat32 = FAT32::new(&mut image).unwrap();
+        assert_eq!(
+            fat32.mount_warnings,
+            ["The backup boot sector (sector 6) differs from the boot sector. Run 'bootsector \
+              backup' to update it."]
+        );
+        fat32.update_backup_boot_sector().unwrap();
+        drop(fat32);
+        assert!(FAT32::new(&mut image).unwrap().mount_warnings.is_empty());
+    }
+
+    #[test]
+    fn failed_create_directory_frees_its_cluster() {
+        let mut fat32 = FAT32::new(new_image()).unwrap();
+        let root = fat32.boot_sector.root_cluster;
+        let free = fat32.free_clusters();
+        assert!(matches!(
+            fat32.create_directory(root, "bad/name"),
+            Err(FsError::InvalidName(_))
+        ));
+        assert_eq!(fat32.free_clusters(), free);
+
+        // Fill the volume and the root directory, then free one cluster for
+        // the new directory; the root can't grow to hold its entry
+        let mut allocated = Vec::new();
+        while let Ok(cluster) = fat32.allocate_cluster() {
+            allocated.push(cluster);
+        }
+        let mut n = 0;
+        while fat32.create_file(root, &format!("F{}", n)).is_ok() {
+            n += 1;
+        }
+        fat32.free_cluster_chain(allocated[0]).unwrap();
+        assert!(matches!(
+            fat32.create_directory(root, "DIR"),
+            Err(FsError::NoSpace)
+        ));
+        assert_eq!(fat32.free_clusters(), 1);
     }
 }
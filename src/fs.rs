@@ -0,0 +1,655 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, Result};
+use crate::fat32::{is_valid_long_name, DirectoryEntry, FAT32};
+use crate::timestamp::Timestamp;
+
+// A mounted FAT32 volume. Paths may be absolute or relative to the current
+// directory, which starts out as the root directory.
+pub struct Fs<D: BlockDevice> {
+    fat32: FAT32<D>,
+    current_dir: u32,
+    // Names of the directories from the root down to the current directory
+    current_path: Vec<String>,
+}
+
+impl Fs<std::fs::File> {
+    // Mounts the image file at `path` for reading and writing
+    pub fn open_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let image_file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Fs::new(image_file)
+    }
+}
+
+impl<D: BlockDevice> Fs<D> {
+    pub fn new(device: D) -> Result<Self> {
+        let fat32 = FAT32::new(device)?;
+        let current_dir = fat32.boot_sector.root_cluster;
+        Ok(Fs {
+            fat32,
+            current_dir,
+            current_path: Vec::new(),
+        })
+    }
+
+    pub fn fat32(&self) -> &FAT32<D> {
+        &self.fat32
+    }
+
+    pub fn fat32_mut(&mut self) -> &mut FAT32<D> {
+        &mut self.fat32
+    }
+
+    // First cluster of the current directory
+    pub fn current_dir(&self) -> u32 {
+        self.current_dir
+    }
+
+    // Absolute path of the current directory, e.g. `/A/B`
+    pub fn current_path(&self) -> String {
+        if self.current_path.is_empty() {
+            return "/".to_string();
+        }
+        self.current_path
+            .iter()
+            .map(|name| format!("/{}", name))
+            .collect()
+    }
+
+    pub fn set_current_dir(&mut self, path: &str) -> Result<()> {
+        let cluster = self.fat32.resolve_directory(self.current_dir, path)?;
+        self.current_path = self.directory_path(cluster)?;
+        self.current_dir = cluster;
+        Ok(())
+    }
+
+    // Names of the directories leading from the root to the directory at
+    // `dir_cluster`, found by following '..' entries upwards
+    fn directory_path(&mut self, mut dir_cluster: u32) -> Result<Vec<String>> {
+        let root_cluster = self.fat32.boot_sector.root_cluster;
+        let mut names = Vec::new();
+
+        // Bounded so a '..' loop on a damaged volume can't hang us
+        for _ in 0..=self.fat32.max_cluster() {
+            if dir_cluster == root_cluster {
+                names.reverse();
+                return Ok(names);
+            }
+            let parent_cluster = self.fat32.parent_directory(dir_cluster)?;
+            let entry = self
+                .fat32
+                .read_directory_entries(parent_cluster)?
+                .into_iter()
+                .find(|entry| {
+                    entry.is_directory()
+                        && entry.first_cluster == dir_cluster
+                        && entry.name != "."
+                        && entry.name != ".."
+                })
+                .ok_or_else(|| {
+                    FsError::corrupted(format!(
+                        "directory at cluster {} is missing from its parent",
+                        dir_cluster
+                    ))
+                })?;
+            names.push(entry.display_name().to_string());
+            dir_cluster = parent_cluster;
+        }
+        Err(FsError::corrupted("'..' entries form a loop"))
+    }
+
+    // Lists the directory `path` names ("." for the current directory)
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>> {
+        let cluster = self.fat32.resolve_directory(self.current_dir, path)?;
+        let mut entries = self.fat32.read_directory_entries(cluster)?;
+        // The volume label is not a file
+        entries.retain(|entry| !entry.is_volume_label());
+        Ok(entries)
+    }
+
+    // Finds the entry `path` refers to. The root directory and paths ending
+    // in '.' or '..' name a directory that has no entry of its own here.
+    pub fn lookup(&mut self, path: &str) -> Result<DirectoryEntry> {
+        let (dir_cluster, name) = self.fat32.resolve_parent(self.current_dir, path)?;
+        if is_special(name) {
+            return Err(is_a_directory(path));
+        }
+        self.fat32
+            .find_entry(dir_cluster, name)?
+            .ok_or_else(|| FsError::NotFound(path.to_string()))
+    }
+
+    // Directory and name a new entry at `path` would get
+    fn new_entry_location<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str)> {
+        let (dir_cluster, name) = self.fat32.resolve_parent(self.current_dir, path)?;
+        if !is_valid_long_name(name) {
+            return Err(FsError::InvalidName(name.to_string()));
+        }
+        if self.fat32.find_entry(dir_cluster, name)?.is_some() {
+            return Err(FsError::AlreadyExists(path.to_string()));
+        }
+        Ok((dir_cluster, name))
+    }
+
+    // Creates an empty file and returns a handle to it
+    pub fn create(&mut self, path: &str) -> Result<FatFile<'_, D>> {
+        let (dir_cluster, name) = self.new_entry_location(path)?;
+        self.fat32.create_file(dir_cluster, name)?;
+        self.open(path)
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<()> {
+        let (dir_cluster, name) = self.new_entry_location(path)?;
+        self.fat32.create_directory(dir_cluster, name)
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<FatFile<'_, D>> {
+        let entry = self.lookup(path)?;
+        if entry.is_directory() {
+            return Err(is_a_directory(path));
+        }
+        Ok(self.open_entry(&entry))
+    }
+
+    // Opens a file from an entry returned by `lookup` or `read_dir`
+    pub fn open_entry(&mut self, entry: &DirectoryEntry) -> FatFile<'_, D> {
+        FatFile {
+            fat32: &mut self.fat32,
+            entry_offset: entry.offset,
+            first_cluster: entry.first_cluster,
+            size: entry.file_size,
+            position: 0,
+            chain: None,
+            dirty: false,
+            written: false,
+            read: false,
+            read_only: entry.is_read_only().then(|| entry.display_name().to_string()),
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        let entry = self.lookup(path)?;
+        if entry.is_directory() {
+            return Err(is_a_directory(path));
+        }
+        if entry.is_read_only() {
+            return Err(read_only(path));
+        }
+        self.fat32.remove_directory_entry(&entry)?;
+        self.fat32.free_cluster_chain(entry.first_cluster)
+    }
+
+    pub fn remove_dir(&mut self, path: &str) -> Result<()> {
+        let (dir_cluster, name) = self.fat32.resolve_parent(self.current_dir, path)?;
+        if is_special(name) {
+            return Err(FsError::InvalidArgument(
+                "Cannot remove special directories '.' or '..'.".to_string(),
+            ));
+        }
+        let entry = self
+            .fat32
+            .find_entry(dir_cluster, name)?
+            .ok_or_else(|| FsError::NotFound(path.to_string()))?;
+        if !entry.is_directory() {
+            return Err(not_a_directory(path));
+        }
+        if entry.is_read_only() {
+            return Err(read_only(path));
+        }
+        if entry.first_cluster == self.current_dir {
+            return Err(FsError::InvalidArgument(
+                "Cannot remove the current directory.".to_string(),
+            ));
+        }
+
+        // Only '.' and '..' may be left
+        let children = self.fat32.read_directory_entries(entry.first_cluster)?;
+        if children.iter().any(|child| child.name != "." && child.name != "..") {
+            return Err(FsError::DirectoryNotEmpty(path.to_string()));
+        }
+
+        self.fat32.remove_directory_entry(&entry)?;
+        self.fat32.free_cluster_chain(entry.first_cluster)
+    }
+
+    // Renames the entry at `path`, moving it when `new_path` is in another
+    // directory
+    pub fn rename(&mut self, path: &str, new_path: &str) -> Result<()> {
+        let (dir_cluster, name) = self.fat32.resolve_parent(self.current_dir, path)?;
+        if is_special(name) {
+            return Err(FsError::InvalidArgument(
+                "Cannot rename special directories '.' or '..'.".to_string(),
+            ));
+        }
+        let entry = self
+            .fat32
+            .find_entry(dir_cluster, name)?
+            .ok_or_else(|| FsError::NotFound(path.to_string()))?;
+        if entry.is_read_only() {
+            return Err(read_only(path));
+        }
+
+        let (new_dir_cluster, new_name) =
+            self.fat32.resolve_parent(self.current_dir, new_path)?;
+        if !is_valid_long_name(new_name) {
+            return Err(FsError::InvalidName(new_name.to_string()));
+        }
+        // A change of case is fine
+        if let Some(other) = self.fat32.find_entry(new_dir_cluster, new_name)? {
+            if other.offset != entry.offset {
+                return Err(FsError::AlreadyExists(new_path.to_string()));
+            }
+        }
+
+        let moves_directory = entry.is_directory() && new_dir_cluster != dir_cluster;
+        if moves_directory && self.is_within(new_dir_cluster, entry.first_cluster)? {
+            return Err(FsError::InvalidArgument(format!(
+                "Cannot move '{}' into itself.",
+                path
+            )));
+        }
+
+        self.fat32.update_entry_name(new_dir_cluster, &entry, new_name)?;
+        if moves_directory {
+            self.fat32
+                .set_parent_directory(entry.first_cluster, new_dir_cluster)?;
+        }
+        if entry.is_directory() {
+            // The current directory may have been renamed or moved
+            self.current_path = self.directory_path(self.current_dir)?;
+        }
+        Ok(())
+    }
+
+    // Sets the read-only, hidden, system and archive bits of the entry at
+    // `path` to those in `attr`. Returns the new attribute byte.
+    pub fn set_attributes(&mut self, path: &str, attr: u8) -> Result<u8> {
+        let entry = self.lookup(path)?;
+        let attr = (entry.attr & !CHANGEABLE_ATTRS) | (attr & CHANGEABLE_ATTRS);
+        self.fat32.set_entry_attr(entry.offset, attr)?;
+        Ok(attr)
+    }
+
+    // Whether the directory at `dir_cluster` is `ancestor` or lies below it
+    fn is_within(&mut self, mut dir_cluster: u32, ancestor: u32) -> Result<bool> {
+        let root_cluster = self.fat32.boot_sector.root_cluster;
+        // Bounded so a '..' loop on a damaged volume can't hang us
+        for _ in 0..=self.fat32.max_cluster() {
+            if dir_cluster == ancestor {
+                return Ok(true);
+            }
+            if dir_cluster == root_cluster {
+                return Ok(false);
+            }
+            dir_cluster = self.fat32.parent_directory(dir_cluster)?;
+        }
+        Err(FsError::corrupted("'..' entries form a loop"))
+    }
+
+    // Writes cached FAT changes to the image. Dropping the Fs does the same
+    // but has to ignore errors, so call this to find out whether it worked.
+    pub fn flush(&mut self) -> Result<()> {
+        self.fat32.flush()
+    }
+}
+
+// Read-only, hidden, system and archive; the directory and volume label
+// bits say what the entry is and can't be changed
+const CHANGEABLE_ATTRS: u8 = 0x01 | 0x02 | 0x04 | 0x20;
+
+// Last path components that don't name a regular entry
+fn is_special(name: &str) -> bool {
+    name.is_empty() || name == "." || name == ".."
+}
+
+fn not_a_directory(name: &str) -> FsError {
+    FsError::NotADirectory(name.to_string())
+}
+
+fn is_a_directory(name: &str) -> FsError {
+    FsError::IsADirectory(name.to_string())
+}
+
+fn read_only(name: &str) -> FsError {
+    FsError::ReadOnly(name.to_string())
+}
+
+// An open file on the volume. The cluster chain is read once, on first use,
+// and extended as the file grows. Changes to the size and first cluster are
+// written to the directory entry on flush and when the handle is dropped;
+// flush also writes the FAT.
+pub struct FatFile<'a, D: BlockDevice> {
+    fat32: &'a mut FAT32<D>,
+    entry_offset: u64,
+    first_cluster: u32,
+    size: u32,
+    position: u64,
+    chain: Option<Vec<u32>>,
+    // The size or first cluster in the directory entry is out of date
+    dirty: bool,
+    // Data was written or read since the entry's times were last updated
+    written: bool,
+    read: bool,
+    // The file's name if it has the read-only attribute; writes to it fail
+    read_only: Option<String>,
+}
+
+impl<D: BlockDevice> FatFile<'_, D> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    // Cluster holding byte `position` of the file, allocating clusters up to
+    // it when the chain is too short
+    fn cluster_at(&mut self, position: u64, allocate: bool) -> Result<Option<u32>> {
+        let index = (position / self.fat32.bytes_per_cluster() as u64) as usize;
+        if self.chain.is_none() {
+            self.chain = Some(self.fat32.get_cluster_chain(self.first_cluster)?);
+        }
+        let chain = self.chain.as_mut().unwrap();
+
+        while chain.len() <= index && allocate {
+            let cluster = self.fat32.allocate_cluster()?;
+            match chain.last() {
+                Some(&last) => self.fat32.set_next_cluster(last, cluster)?,
+                None => {
+                    // The first write to an empty file allocates its first cluster
+                    self.first_cluster = cluster;
+                    self.dirty = true;
+                }
+            }
+            chain.push(cluster);
+        }
+        Ok(chain.get(index).copied())
+    }
+
+    // Writes `data` at `position` without moving the file position; returns
+    // how much fit in the cluster holding `position`
+    fn write_at(&mut self, position: u64, data: &[u8]) -> Result<usize> {
+        let bytes_per_cluster = self.fat32.bytes_per_cluster();
+        let cluster_offset = (position % bytes_per_cluster as u64) as u32;
+        let len = std::cmp::min(data.len(), (bytes_per_cluster - cluster_offset) as usize);
+
+        let cluster = self.cluster_at(position, true)?.unwrap();
+        self.fat32
+            .write_cluster_data(cluster, cluster_offset, &data[..len])?;
+
+        let end = (position + len as u64) as u32;
+        if end > self.size {
+            self.size = end;
+            self.dirty = true;
+        }
+        Ok(len)
+    }
+
+    // Writes the size and first cluster to the directory entry if they
+    // changed, and records when the file was last written or read
+    fn update_entry(&mut self) -> Result<()> {
+        if self.dirty {
+            self.fat32
+                .update_entry(self.entry_offset, self.first_cluster, self.size)?;
+            self.dirty = false;
+        }
+        if self.written || self.read {
+            let now = Timestamp::now();
+            let modified = if self.written { Some(now) } else { None };
+            self.fat32
+                .set_entry_times(self.entry_offset, None, modified, Some(now))?;
+            self.written = false;
+            self.read = false;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Read for FatFile<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size as u64 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Read at most to the end of the current cluster
+        let bytes_per_cluster = self.fat32.bytes_per_cluster();
+        let cluster_offset = (self.position % bytes_per_cluster as u64) as u32;
+        let to_read = [
+            buf.len() as u64,
+            self.size as u64 - self.position,
+            (bytes_per_cluster - cluster_offset) as u64,
+        ]
+        .into_iter()
+        .min()
+        .unwrap() as usize;
+
+        let cluster = match self.cluster_at(self.position, false)? {
+            Some(cluster) => cluster,
+            None => {
+                return Err(FsError::corrupted(format!(
+                    "file at cluster {} is shorter than its size",
+                    self.first_cluster
+                ))
+                .into())
+            }
+        };
+        self.fat32
+            .read_cluster_data(cluster, cluster_offset, &mut buf[..to_read])?;
+
+        self.position += to_read as u64;
+        self.read = true;
+        Ok(to_read)
+    }
+}
+
+impl<D: BlockDevice> Write for FatFile<'_, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(name) = &self.read_only {
+            return Err(read_only(name).into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.position + buf.len() as u64 > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "FAT32 files are limited to 4 GiB.",
+            ));
+        }
+
+        // Writing past the end leaves a gap that has to read back as zeros
+        let zeros = [0u8; 512];
+        while (self.size as u64) < self.position {
+            let gap = std::cmp::min(self.position - self.size as u64, zeros.len() as u64);
+            self.write_at(self.size as u64, &zeros[..gap as usize])?;
+        }
+
+        let written = self.write_at(self.position, buf)?;
+        self.position += written as u64;
+        self.written = true;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.update_entry()?;
+        Ok(self.fat32.flush()?)
+    }
+}
+
+impl<D: BlockDevice> Seek for FatFile<'_, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.size as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of the file.",
+            )),
+        }
+    }
+}
+
+impl<D: BlockDevice> Drop for FatFile<'_, D> {
+    fn drop(&mut self) {
+        // Errors can't be reported here; call flush to see them
+        let _ = self.update_entry();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsck;
+    use crate::mkfs::{self, MkfsOptions};
+
+    // A freshly formatted volume held in memory
+    fn new_volume() -> Fs<Vec<u8>> {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        mkfs::format(&mut image, &MkfsOptions::default()).unwrap();
+        Fs::new(image).unwrap()
+    }
+
+    #[test]
+    fn failed_rename_keeps_the_old_name() {
+        let mut fs = new_volume();
+        fs.create("F0").unwrap().write_all(b"data").unwrap();
+
+        // Use up every cluster, then every slot in the root directory
+        let mut big = fs.create("BIG").unwrap();
+        assert!(big.write_all(&vec![0u8; 64 * 1024 * 1024]).is_err());
+        drop(big);
+        let mut n = 1;
+        while fs.create(&format!("F{}", n)).is_ok() {
+            n += 1;
+        }
+
+        // The long name needs more slots than the old entry frees
+        let result = fs.rename("F0", "this_is_a_long_name.txt");
+        assert!(matches!(result, Err(FsError::NoSpace)), "{:?}", result.err());
+        assert_eq!(fs.lookup("F0").unwrap().file_size, 4);
+        assert!(fs.lookup("this_is_a_long_name.txt").is_err());
+
+        // A name that fits in the old slot still works
+        fs.rename("F0", "G0").unwrap();
+        assert_eq!(fs.lookup("G0").unwrap().file_size, 4);
+        fs.flush().unwrap();
+        assert!(fsck::check(fs.fat32_mut()).unwrap().is_clean());
+    }
+
+    #[test]
+    fn dropping_the_volume_writes_the_fat() {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        mkfs::format(&mut image, &MkfsOptions::default()).unwrap();
+        {
+            let mut fs = Fs::new(&mut image).unwrap();
+            fs.create_dir("docs").unwrap();
+            let mut file = fs.create("docs/notes.txt").unwrap();
+            file.write_all(&[b'x'; 5000]).unwrap();
+        }
+
+        let mut fs = Fs::new(&mut image).unwrap();
+        let report = fsck::check(fs.fat32_mut()).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let mut data = Vec::new();
+        fs.open("docs/notes.txt").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, [b'x'; 5000]);
+    }
+
+    #[test]
+    fn seeking_and_writing_past_the_end() {
+        let mut fs = new_volume();
+        let mut file = fs.create("sparse.bin").unwrap();
+        file.write_all(b"abc").unwrap();
+        // Leaves a gap spanning several clusters
+        assert_eq!(file.seek(SeekFrom::Start(2000)).unwrap(), 2000);
+        file.write_all(b"xyz").unwrap();
+        assert_eq!(file.size(), 2003);
+
+        assert_eq!(file.seek(SeekFrom::End(-3)).unwrap(), 2000);
+        let mut tail = [0u8; 3];
+        file.read_exact(&mut tail).unwrap();
+        assert_eq!(&tail, b"xyz");
+        assert_eq!(file.read(&mut tail).unwrap(), 0);
+        assert!(file.seek(SeekFrom::Current(-3000)).is_err());
+
+        // Seeking alone doesn't change the size
+        file.seek(SeekFrom::Start(10_000)).unwrap();
+        assert_eq!(file.size(), 2003);
+        drop(file);
+
+        let mut data = Vec::new();
+        fs.open("sparse.bin").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 2003);
+        assert_eq!(&data[..3], b"abc");
+        assert!(data[3..2000].iter().all(|&b| b == 0));
+        assert_eq!(&data[2000..], b"xyz");
+    }
+
+    #[test]
+    fn overwriting_keeps_the_rest_of_the_file() {
+        let mut fs = new_volume();
+        let mut file = fs.create("file.txt").unwrap();
+        file.write_all(&[b'a'; 1200]).unwrap();
+        file.seek(SeekFrom::Start(510)).unwrap();
+        file.write_all(b"----").unwrap();
+        drop(file);
+
+        let mut data = Vec::new();
+        fs.open("file.txt").unwrap().read_to_end(&mut data).unwrap();
+        let mut expected = vec![b'a'; 1200];
+        expected[510..514].copy_from_slice(b"----");
+        assert_eq!(data, expected);
+        assert_eq!(fs.lookup("file.txt").unwrap().file_size, 1200);
+    }
+
+    #[test]
+    fn read_only_files_cannot_be_written() {
+        let mut fs = new_volume();
+        fs.create("ro.txt").unwrap().write_all(b"keep").unwrap();
+        fs.set_attributes("ro.txt", 0x01).unwrap();
+
+        let mut file = fs.open("ro.txt").unwrap();
+        let result = file.write_all(b"gone").map_err(FsError::from);
+        assert!(matches!(result, Err(FsError::ReadOnly(_))), "{:?}", result);
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"keep");
+        drop(file);
+
+        fs.set_attributes("ro.txt", 0).unwrap();
+        fs.open("ro.txt").unwrap().write_all(b"new!").unwrap();
+        let mut data = Vec::new();
+        fs.open("ro.txt").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"new!");
+    }
+
+    #[test]
+    fn directory_without_clusters_is_reported_as_corrupted() {
+        let mut fs = new_volume();
+        fs.create_dir("X").unwrap();
+        let entry = fs.lookup("X").unwrap();
+        fs.fat32_mut().update_entry(entry.offset, 0, 0).unwrap();
+
+        for result in [
+            fs.create("X/bar").map(drop),
+            fs.create_dir("X/sub"),
+            fs.read_dir("X").map(drop),
+            fs.set_current_dir("X"),
+        ] {
+            assert!(matches!(result, Err(FsError::Corrupted { .. })));
+        }
+    }
+}
@@ -0,0 +1,743 @@
+use std::fmt;
+
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, Result};
+use crate::fat32::{DirectoryEntry, FAT32};
+
+// Inconsistencies found by `check`. Problems that refer to a directory
+// entry carry it, so a repair can rewrite or remove it.
+#[derive(Debug, Clone)]
+pub enum Problem {
+    // The entry's first cluster lies outside the data region
+    BadFirstCluster { path: String, entry: DirectoryEntry },
+    // A cluster of the chain links to a free, bad or out of range cluster
+    BrokenChain { path: String, cluster: u32, next: u32 },
+    // The chain runs back into itself after `cluster`
+    ChainLoop { path: String, cluster: u32 },
+    // The chain runs into a cluster already used by `other`. `previous` is
+    // the cluster linking to it, or None when the chain starts there.
+    CrossLinked {
+        path: String,
+        other: String,
+        cluster: u32,
+        previous: Option<u32>,
+        entry: Option<DirectoryEntry>,
+    },
+    // The chain is longer or shorter than the file size needs
+    SizeMismatch {
+        path: String,
+        entry: DirectoryEntry,
+        clusters: u32,
+        expected: u32,
+    },
+    // A '.' or '..' entry that is missing or points at the wrong cluster
+    BadDotEntry {
+        path: String,
+        name: &'static str,
+        expected: u32,
+        entry: Option<DirectoryEntry>,
+    },
+    // Allocated clusters no file or directory refers to
+    LostChain { first: u32, clusters: u32 },
+    // A FAT copy differs from the active FAT
+    FatCopyDiverges { index: u8, active: u8 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadFirstCluster { path, entry } => write!(
+                f,
+                "'{}' starts at invalid cluster {}.",
+                path, entry.first_cluster
+            ),
+            Problem::BrokenChain { path, cluster, next } => write!(
+                f,
+                "Cluster chain of '{}' is broken after cluster {} (next: 0x{:08X}).",
+                path, cluster, next
+            ),
+            Problem::ChainLoop { path, cluster } => write!(
+                f,
+                "Cluster chain of '{}' loops after cluster {}.",
+                path, cluster
+            ),
+            Problem::CrossLinked {
+                path,
+                other,
+                cluster,
+                ..
+            } => write!(
+                f,
+                "'{}' is cross-linked with '{}' at cluster {}.",
+                path, other, cluster
+            ),
+            Problem::SizeMismatch {
+                path,
+                entry,
+                clusters,
+                expected,
+            } => write!(
+                f,
+                "'{}' is {} bytes long but has {} clusters (expected {}).",
+                path, entry.file_size, clusters, expected
+            ),
+            Problem::BadDotEntry {
+                path,
+                name,
+                expected,
+                entry: Some(entry),
+            } => write!(
+                f,
+                "'{}' entry of '{}' points to cluster {} (expected {}).",
+                name, path, entry.first_cluster, expected
+            ),
+            Problem::BadDotEntry {
+                path,
+                name,
+                entry: None,
+                ..
+            } => write!(f, "'{}' has no '{}' entry.", path, name),
+            Problem::LostChain { first, clusters } => write!(
+                f,
+                "{} lost cluster(s) in a chain starting at cluster {}.",
+                clusters, first
+            ),
+            Problem::FatCopyDiverges { index, active } => {
+                write!(f, "FAT #{} differs from active FAT #{}.", index, active)
+            }
+        }
+    }
+}
+
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub directories: u32,
+    pub files: u32,
+    // Clusters reachable from the root directory
+    pub used_clusters: u32,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Walks the whole tree from the root directory and checks it against the
+// FAT. Nothing is written, apart from flushing cached FAT changes.
+pub fn check<D: BlockDevice>(fat32: &mut FAT32<D>) -> Result<Report> {
+    let mut checker = Checker {
+        owners: vec![None; fat32.max_cluster() as usize + 1],
+        owner_paths: Vec::new(),
+        report: Report {
+            problems: Vec::new(),
+            directories: 0,
+            files: 0,
+            used_clusters: 0,
+        },
+        fat32,
+    };
+
+    checker.walk_tree()?;
+    checker.find_lost_chains()?;
+
+    let active = checker.fat32.active_fat();
+    for index in checker.fat32.find_divergent_fats()? {
+        checker
+            .report
+            .problems
+            .push(Problem::FatCopyDiverges { index, active });
+    }
+
+    Ok(checker.report)
+}
+
+struct Checker<'a, D: BlockDevice> {
+    fat32: &'a mut FAT32<D>,
+    // Index into `owner_paths` of the file or directory using each cluster
+    owners: Vec<Option<usize>>,
+    owner_paths: Vec<String>,
+    report: Report,
+}
+
+impl<D: BlockDevice> Checker<'_, D> {
+    fn walk_tree(&mut self) -> Result<()> {
+        let root_cluster = self.fat32.boot_sector.root_cluster;
+        // Directories still to visit: path, parent cluster and the entry
+        // naming them (None for the root directory)
+        let mut pending = vec![(String::from("/"), root_cluster, None)];
+
+        while let Some((path, parent_cluster, dir_entry)) = pending.pop() {
+            let dir_cluster = match &dir_entry {
+                Some(DirectoryEntry { first_cluster, .. }) => *first_cluster,
+                None => root_cluster,
+            };
+            self.report.directories += 1;
+            let cluster_chain = self.claim_chain(&path, dir_cluster, dir_entry.as_ref())?;
+            if cluster_chain.is_empty() {
+                continue;
+            }
+            let entries = self.fat32.read_directory_clusters(&cluster_chain)?;
+
+            if dir_cluster != root_cluster {
+                let dotdot = if parent_cluster == root_cluster {
+                    0
+                } else {
+                    parent_cluster
+                };
+                self.check_dot_entry(&path, &entries, ".", dir_cluster);
+                self.check_dot_entry(&path, &entries, "..", dotdot);
+            }
+
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." || entry.attr & 0x08 != 0 {
+                    // Volume labels have no data
+                    continue;
+                }
+                let child_path = if path == "/" {
+                    format!("/{}", entry.display_name())
+                } else {
+                    format!("{}/{}", path, entry.display_name())
+                };
+
+                let first_cluster = entry.first_cluster;
+                if first_cluster != 0
+                    && (first_cluster < 2 || first_cluster > self.fat32.max_cluster())
+                {
+                    self.report.problems.push(Problem::BadFirstCluster {
+                        path: child_path,
+                        entry,
+                    });
+                    continue;
+                }
+
+                if entry.is_directory() {
+                    if first_cluster == 0 {
+                        self.report.problems.push(Problem::BadFirstCluster {
+                            path: child_path,
+                            entry,
+                        });
+                        continue;
+                    }
+                    // Checked when the directory itself is visited
+                    pending.push((child_path, dir_cluster, Some(entry)));
+                } else {
+                    self.report.files += 1;
+                    self.check_file(child_path, entry)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Marks the clusters of the chain starting at `start` as used by `path`
+    // and returns them. The walk stops at the first cluster that would make
+    // the chain invalid, so the returned clusters are safe to read.
+    fn claim_chain(
+        &mut self,
+        path: &str,
+        start: u32,
+        entry: Option<&DirectoryEntry>,
+    ) -> Result<Vec<u32>> {
+        let owner = self.owner_paths.len();
+        self.owner_paths.push(path.to_string());
+        let max_cluster = self.fat32.max_cluster();
+
+        let mut chain: Vec<u32> = Vec::new();
+        let mut cluster = start;
+        loop {
+            match self.owners[cluster as usize] {
+                Some(other) if other == owner => {
+                    self.report.problems.push(Problem::ChainLoop {
+                        path: path.to_string(),
+                        cluster: *chain.last().unwrap(),
+                    });
+                    break;
+                }
+                Some(other) => {
+                    self.report.problems.push(Problem::CrossLinked {
+                        path: path.to_string(),
+                        other: self.owner_paths[other].clone(),
+                        cluster,
+                        previous: chain.last().copied(),
+                        entry: entry.cloned(),
+                    });
+                    break;
+                }
+                None => {}
+            }
+
+            self.owners[cluster as usize] = Some(owner);
+            self.report.used_clusters += 1;
+            chain.push(cluster);
+
+            let next = self.fat32.get_next_cluster(cluster)?;
+            if next >= 0x0FFFFFF8 {
+                break;
+            }
+            if next < 2 || next > max_cluster {
+                self.report.problems.push(Problem::BrokenChain {
+                    path: path.to_string(),
+                    cluster,
+                    next,
+                });
+                break;
+            }
+            cluster = next;
+        }
+
+        Ok(chain)
+    }
+
+    // Groups allocated clusters that were not reached from the root into
+    // chains. Cycles without a head are reported from their lowest cluster.
+    fn find_lost_chains(&mut self) -> Result<()> {
+        let max_cluster = self.fat32.max_cluster();
+        let is_lost = |owners: &[Option<usize>], value: u32, cluster: u32| {
+            owners[cluster as usize].is_none() && value != 0 && value != 0x0FFFFFF7
+        };
+
+        let mut lost = vec![false; max_cluster as usize + 1];
+        let mut has_predecessor = vec![false; max_cluster as usize + 1];
+        for cluster in 2..=max_cluster {
+            let value = self.fat32.get_next_cluster(cluster)?;
+            lost[cluster as usize] = is_lost(&self.owners, value, cluster);
+        }
+        for cluster in 2..=max_cluster {
+            let next = self.fat32.get_next_cluster(cluster)?;
+            if lost[cluster as usize] && (2..=max_cluster).contains(&next) {
+                has_predecessor[next as usize] = true;
+            }
+        }
+
+        // Heads first, then whatever is left over in cycles
+        let heads = (2..=max_cluster).filter(|&c| !has_predecessor[c as usize]);
+        let order: Vec<u32> = heads.chain(2..=max_cluster).collect();
+        for first in order {
+            if !lost[first as usize] {
+                continue;
+            }
+            let mut clusters = 0;
+            let mut cluster = first;
+            while (2..=max_cluster).contains(&cluster) && lost[cluster as usize] {
+                lost[cluster as usize] = false;
+                clusters += 1;
+                cluster = self.fat32.get_next_cluster(cluster)?;
+            }
+            self.report
+                .problems
+                .push(Problem::LostChain { first, clusters });
+        }
+
+        Ok(())
+    }
+
+    fn check_file(&mut self, path: String, entry: DirectoryEntry) -> Result<()> {
+        let clusters = if entry.first_cluster == 0 {
+            0
+        } else {
+            self.claim_chain(&path, entry.first_cluster, Some(&entry))?
+                .len() as u32
+        };
+        let expected = entry.file_size.div_ceil(self.fat32.bytes_per_cluster());
+        if clusters != expected {
+            self.report.problems.push(Problem::SizeMismatch {
+                path,
+                entry,
+                clusters,
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_dot_entry(
+        &mut self,
+        path: &str,
+        entries: &[DirectoryEntry],
+        name: &'static str,
+        expected: u32,
+    ) {
+        let entry = entries.iter().find(|entry| entry.name == name);
+        if entry.is_none_or(|entry| entry.first_cluster != expected) {
+            self.report.problems.push(Problem::BadDotEntry {
+                path: path.to_string(),
+                name,
+                expected,
+                entry: entry.cloned(),
+            });
+        }
+    }
+}
+
+// A change made to the image to resolve a problem found by `check`
+#[derive(Debug, Clone)]
+pub enum Fix {
+    // Ends the chain after `cluster`
+    TruncateChain { path: String, cluster: u32 },
+    // Turns the file into an empty one without touching its old chain
+    ClearFirstCluster { path: String, entry: DirectoryEntry },
+    // Removes a directory entry without touching its clusters
+    RemoveEntry { path: String, entry: DirectoryEntry },
+    // Keeps the first `clusters` clusters of the file and frees the rest
+    TruncateFile {
+        path: String,
+        entry: DirectoryEntry,
+        clusters: u32,
+    },
+    SetFileSize {
+        path: String,
+        entry: DirectoryEntry,
+        size: u32,
+    },
+    SetDotEntry {
+        path: String,
+        name: &'static str,
+        entry: DirectoryEntry,
+        cluster: u32,
+    },
+    // Lost chains are either freed or saved as a file in FOUND.000
+    FreeChain { first: u32, clusters: u32 },
+    SalvageChain {
+        first: u32,
+        clusters: u32,
+        name: String,
+    },
+    SyncFatCopies { active: u8 },
+}
+
+// Directory in the root that salvaged chains are saved in
+const FOUND_DIR: &str = "FOUND.000";
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fix::TruncateChain { path, cluster } => write!(
+                f,
+                "End the cluster chain of '{}' at cluster {}.",
+                path, cluster
+            ),
+            Fix::ClearFirstCluster { path, .. } => {
+                write!(f, "Truncate '{}' to 0 bytes.", path)
+            }
+            Fix::RemoveEntry { path, .. } => write!(f, "Remove the entry for '{}'.", path),
+            Fix::TruncateFile { path, clusters, .. } => write!(
+                f,
+                "Free the clusters of '{}' after the first {}.",
+                path, clusters
+            ),
+            Fix::SetFileSize { path, size, .. } => {
+                write!(f, "Set the size of '{}' to {} bytes.", path, size)
+            }
+            Fix::SetDotEntry {
+                path,
+                name,
+                cluster,
+                ..
+            } => write!(
+                f,
+                "Point the '{}' entry of '{}' to cluster {}.",
+                name, path, cluster
+            ),
+            Fix::FreeChain { first, clusters } => write!(
+                f,
+                "Free {} lost cluster(s) starting at cluster {}.",
+                clusters, first
+            ),
+            Fix::SalvageChain {
+                first,
+                clusters,
+                name,
+            } => write!(
+                f,
+                "Save {} lost cluster(s) starting at cluster {} as '/{}/{}'.",
+                clusters, first, FOUND_DIR, name
+            ),
+            Fix::SyncFatCopies { active } => {
+                write!(f, "Copy FAT #{} over the other FAT copies.", active)
+            }
+        }
+    }
+}
+
+// Works out the fixes for the problems in `report`. Lost chains are saved in
+// FOUND.000 when `salvage` is set and freed otherwise. Problems that can't
+// be repaired automatically, such as a missing '.' entry, get no fix.
+pub fn plan_repairs<D: BlockDevice>(
+    fat32: &mut FAT32<D>,
+    report: &Report,
+    salvage: bool,
+) -> Result<Vec<Fix>> {
+    let mut chain_fixes = Vec::new();
+    let mut entry_fixes = Vec::new();
+    let mut lost_fixes = Vec::new();
+    let mut sync_fix = None;
+    // Entries that are cleared or removed need no further fixes
+    let mut replaced_entries = Vec::new();
+    let mut next_salvage_number = 0;
+
+    for problem in &report.problems {
+        match problem {
+            Problem::BrokenChain { path, cluster, .. } | Problem::ChainLoop { path, cluster } => {
+                chain_fixes.push(Fix::TruncateChain {
+                    path: path.clone(),
+                    cluster: *cluster,
+                });
+            }
+            Problem::CrossLinked {
+                path,
+                previous: Some(previous),
+                ..
+            } => {
+                chain_fixes.push(Fix::TruncateChain {
+                    path: path.clone(),
+                    cluster: *previous,
+                });
+            }
+            Problem::CrossLinked {
+                path,
+                previous: None,
+                entry: Some(entry),
+                ..
+            }
+            | Problem::BadFirstCluster { path, entry } => {
+                replaced_entries.push(entry.offset);
+                chain_fixes.push(if entry.is_directory() {
+                    Fix::RemoveEntry {
+                        path: path.clone(),
+                        entry: entry.clone(),
+                    }
+                } else {
+                    Fix::ClearFirstCluster {
+                        path: path.clone(),
+                        entry: entry.clone(),
+                    }
+                });
+            }
+            Problem::CrossLinked { .. } => {}
+            Problem::SizeMismatch {
+                path,
+                entry,
+                clusters,
+                expected,
+            } => {
+                if clusters > expected {
+                    entry_fixes.push(Fix::TruncateFile {
+                        path: path.clone(),
+                        entry: entry.clone(),
+                        clusters: *expected,
+                    });
+                } else {
+                    let size = clusters * fat32.bytes_per_cluster();
+                    entry_fixes.push(Fix::SetFileSize {
+                        path: path.clone(),
+                        entry: entry.clone(),
+                        size,
+                    });
+                }
+            }
+            Problem::BadDotEntry {
+                path,
+                name,
+                expected,
+                entry: Some(entry),
+            } => {
+                entry_fixes.push(Fix::SetDotEntry {
+                    path: path.clone(),
+                    name,
+                    entry: entry.clone(),
+                    cluster: *expected,
+                });
+            }
+            Problem::BadDotEntry { entry: None, .. } => {}
+            Problem::LostChain { first, clusters } => {
+                if salvage {
+                    let name = next_salvage_name(fat32, &mut next_salvage_number)?;
+                    lost_fixes.push(Fix::SalvageChain {
+                        first: *first,
+                        clusters: *clusters,
+                        name,
+                    });
+                } else {
+                    lost_fixes.push(Fix::FreeChain {
+                        first: *first,
+                        clusters: *clusters,
+                    });
+                }
+            }
+            Problem::FatCopyDiverges { active, .. } => {
+                sync_fix = Some(Fix::SyncFatCopies { active: *active });
+            }
+        }
+    }
+
+    entry_fixes.retain(|fix| match fix {
+        Fix::TruncateFile { entry, .. } | Fix::SetFileSize { entry, .. } => {
+            !replaced_entries.contains(&entry.offset)
+        }
+        _ => true,
+    });
+
+    // Chains are fixed before anything that follows them
+    let mut fixes = chain_fixes;
+    fixes.extend(entry_fixes);
+    fixes.extend(lost_fixes);
+    fixes.extend(sync_fix);
+    Ok(fixes)
+}
+
+// Next FILEnnnn.CHK name not used in FOUND.000 yet
+fn next_salvage_name<D: BlockDevice>(fat32: &mut FAT32<D>, number: &mut u32) -> Result<String> {
+    let root_cluster = fat32.boot_sector.root_cluster;
+    let existing = match fat32.find_entry(root_cluster, FOUND_DIR)? {
+        Some(found) if found.is_directory() => fat32.read_directory_entries(found.first_cluster)?,
+        _ => Vec::new(),
+    };
+    loop {
+        let name = format!("FILE{:04}.CHK", number);
+        *number += 1;
+        if !existing.iter().any(|entry| entry.matches(&name)) {
+            return Ok(name);
+        }
+    }
+}
+
+// Applies fixes from `plan_repairs` in order and writes the FAT back
+pub fn apply_repairs<D: BlockDevice>(fat32: &mut FAT32<D>, fixes: &[Fix]) -> Result<()> {
+    for fix in fixes {
+        match fix {
+            Fix::TruncateChain { cluster, .. } => {
+                fat32.set_next_cluster(*cluster, 0x0FFFFFF8)?;
+            }
+            Fix::ClearFirstCluster { entry, .. } => {
+                fat32.update_entry(entry.offset, 0, 0)?;
+            }
+            Fix::RemoveEntry { entry, .. } => {
+                fat32.remove_directory_entry(entry)?;
+            }
+            Fix::TruncateFile {
+                entry, clusters, ..
+            } => {
+                let cluster_chain = fat32.get_cluster_chain(entry.first_cluster)?;
+                let keep = *clusters as usize;
+                if keep == 0 {
+                    fat32.update_entry(entry.offset, 0, entry.file_size)?;
+                } else {
+                    fat32.set_next_cluster(cluster_chain[keep - 1], 0x0FFFFFF8)?;
+                }
+                if let Some(&first_freed) = cluster_chain.get(keep) {
+                    fat32.free_cluster_chain(first_freed)?;
+                }
+            }
+            Fix::SetFileSize { entry, size, .. } => {
+                fat32.update_entry(entry.offset, entry.first_cluster, *size)?;
+            }
+            Fix::SetDotEntry { entry, cluster, .. } => {
+                fat32.update_entry(entry.offset, *cluster, 0)?;
+            }
+            Fix::FreeChain { first, clusters } => {
+                // Walk a counted number of clusters, as lost chains may loop
+                let mut cluster = *first;
+                for _ in 0..*clusters {
+                    let next = fat32.get_next_cluster(cluster)?;
+                    fat32.set_next_cluster(cluster, 0)?;
+                    cluster = next;
+                }
+            }
+            Fix::SalvageChain {
+                first,
+                clusters,
+                name,
+            } => {
+                let mut cluster = *first;
+                for _ in 1..*clusters {
+                    cluster = fat32.get_next_cluster(cluster)?;
+                }
+                fat32.set_next_cluster(cluster, 0x0FFFFFF8)?;
+
+                let found_cluster = found_directory(fat32)?;
+                fat32.create_file(found_cluster, name)?;
+                let file = fat32
+                    .find_entry(found_cluster, name)?
+                    .ok_or_else(|| FsError::NotFound(name.clone()))?;
+                let size = clusters.saturating_mul(fat32.bytes_per_cluster());
+                fat32.update_entry(file.offset, *first, size)?;
+            }
+            Fix::SyncFatCopies { .. } => fat32.sync_fat_copies()?,
+        }
+    }
+
+    fat32.flush()
+}
+
+// First cluster of FOUND.000, which is created when missing
+fn found_directory<D: BlockDevice>(fat32: &mut FAT32<D>) -> Result<u32> {
+    let root_cluster = fat32.boot_sector.root_cluster;
+    if let Some(found) = fat32.find_entry(root_cluster, FOUND_DIR)? {
+        if found.is_directory() {
+            return Ok(found.first_cluster);
+        }
+        return Err(FsError::NotADirectory(format!("/{}", FOUND_DIR)));
+    }
+    fat32.create_directory(root_cluster, FOUND_DIR)?;
+    fat32
+        .find_entry(root_cluster, FOUND_DIR)?
+        .map(|found| found.first_cluster)
+        .ok_or_else(|| FsError::NotFound(format!("/{}", FOUND_DIR)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::fs::Fs;
+    use crate::mkfs::{self, MkfsOptions};
+
+    #[test]
+    fn chain_loops_and_bad_dot_entries_are_found_and_repaired() {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        mkfs::format(&mut image, &MkfsOptions::default()).unwrap();
+        let mut fs = Fs::new(image).unwrap();
+        let cluster_bytes = fs.fat32().bytes_per_cluster() as usize;
+        fs.create("loop.bin")
+            .unwrap()
+            .write_all(&vec![b'x'; 3 * cluster_bytes])
+            .unwrap();
+        fs.create_dir("dir").unwrap();
+        fs.create_dir("dir/sub").unwrap();
+
+        // Link the file's last cluster back to its first
+        let file = fs.lookup("loop.bin").unwrap();
+        let chain = fs.fat32().get_cluster_chain(file.first_cluster).unwrap();
+        fs.fat32_mut().set_next_cluster(chain[2], chain[0]).unwrap();
+        // Point '..' of dir/sub at the file instead of dir
+        let dir = fs.lookup("dir").unwrap();
+        let sub = fs.lookup("dir/sub").unwrap();
+        let fat32 = fs.fat32_mut();
+        let dot_dot = fat32
+            .read_directory_entries(sub.first_cluster)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.name == "..")
+            .unwrap();
+        fat32.update_entry(dot_dot.offset, chain[0], 0).unwrap();
+        fat32.flush().unwrap();
+
+        let report = check(fat32).unwrap();
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::ChainLoop { path, cluster } if path == "/loop.bin" && *cluster == chain[2]
+        )));
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::BadDotEntry { name: "..", expected, .. } if *expected == dir.first_cluster
+        )));
+
+        let fixes = plan_repairs(fat32, &report, false).unwrap();
+        apply_repairs(fat32, &fixes).unwrap();
+        let report = check(fat32).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
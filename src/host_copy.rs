@@ -0,0 +1,167 @@
+use std::fs::Metadata;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, Result};
+use crate::fat32::DirectoryEntry;
+use crate::fs::Fs;
+
+// A file or directory `copy_children` has just created in the image
+pub struct Copied<'a> {
+    pub host: &'a Path,
+    pub image_path: &'a str,
+    pub entry: &'a DirectoryEntry,
+    pub metadata: &'a Metadata,
+    // Bytes copied, or None for a directory
+    pub bytes: Option<u64>,
+}
+
+// Copies the host file `host` to a new file at `image_path` and returns the
+// number of bytes copied. A copy that fails partway, e.g. because the volume
+// is full, is removed again.
+pub fn copy_file<D: BlockDevice>(fs: &mut Fs<D>, host: &Path, image_path: &str) -> Result<u64> {
+    let mut host_file = std::fs::File::open(host).map_err(|e| host_error(host, e))?;
+    let mut file = fs.create(image_path)?;
+    let result = io::copy(&mut host_file, &mut file).and_then(|bytes| file.flush().map(|()| bytes));
+    drop(file);
+    if result.is_err() {
+        let _ = fs.remove(image_path);
+    }
+    Ok(result?)
+}
+
+// Copies the contents of the host directory `host` into the image directory
+// `image_dir` ("" for the root), in name order. `on_copy` is called for each
+// file once it is copied and for each directory before its contents are.
+// Symlinks are not followed: they, and anything else that is neither a file
+// nor a directory, are added to `skipped` instead of being copied.
+pub fn copy_children<D, F>(
+    fs: &mut Fs<D>,
+    host: &Path,
+    image_dir: &str,
+    skipped: &mut Vec<PathBuf>,
+    on_copy: &mut F,
+) -> Result<()>
+where
+    D: BlockDevice,
+    F: FnMut(&mut Fs<D>, &Copied) -> Result<()>,
+{
+    let mut children = std::fs::read_dir(host)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(|e| host_error(host, e))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let host_child = child.path();
+        let name = child.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return Err(FsError::InvalidName(host_child.display().to_string())),
+        };
+        let image_path = join_image_path(image_dir, name);
+        let metadata =
+            std::fs::symlink_metadata(&host_child).map_err(|e| host_error(&host_child, e))?;
+        if !metadata.is_dir() && !metadata.is_file() {
+            skipped.push(host_child);
+            continue;
+        }
+
+        let bytes = if metadata.is_dir() {
+            fs.create_dir(&image_path)?;
+            None
+        } else {
+            Some(copy_file(fs, &host_child, &image_path)?)
+        };
+        let entry = fs.lookup(&image_path)?;
+        let copied = Copied {
+            host: &host_child,
+            image_path: &image_path,
+            entry: &entry,
+            metadata: &metadata,
+            bytes,
+        };
+        on_copy(fs, &copied)?;
+
+        if metadata.is_dir() {
+            copy_children(fs, &host_child, &image_path, skipped, on_copy)?;
+        }
+    }
+    Ok(())
+}
+
+// Removes the file or whole directory tree at `image_path`, to undo a copy
+// that failed partway
+pub fn remove_tree<D: BlockDevice>(fs: &mut Fs<D>, image_path: &str) -> Result<()> {
+    if !fs.lookup(image_path)?.is_directory() {
+        return fs.remove(image_path);
+    }
+    for entry in fs.read_dir(image_path)? {
+        let name = entry.display_name();
+        // Skip the entries every directory has
+        if name == "." || name == ".." {
+            continue;
+        }
+        remove_tree(fs, &join_image_path(image_path, name))?;
+    }
+    fs.remove_dir(image_path)
+}
+
+pub fn join_image_path(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// Names the host file in errors that are about the file itself
+pub fn host_error(path: &Path, e: io::Error) -> FsError {
+    match e.kind() {
+        io::ErrorKind::NotFound => FsError::NotFound(path.display().to_string()),
+        io::ErrorKind::AlreadyExists => FsError::AlreadyExists(path.display().to_string()),
+        _ => FsError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsck;
+    use crate::mkfs::{self, MkfsOptions};
+
+    #[test]
+    fn failed_copies_are_removed() {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        mkfs::format(&mut image, &MkfsOptions::default()).unwrap();
+        let mut fs = Fs::new(image).unwrap();
+
+        let host = std::env::temp_dir().join(format!("filesys-host-copy-{}", std::process::id()));
+        std::fs::create_dir_all(host.join("sub")).unwrap();
+        std::fs::write(host.join("small.txt"), b"small").unwrap();
+        std::fs::write(host.join("sub/big.bin"), vec![1u8; 1024 * 1024]).unwrap();
+
+        // Leave room for the small file but not the big one
+        let free = fs.fat32().free_clusters() as usize * fs.fat32().bytes_per_cluster() as usize;
+        fs.create("FILLER")
+            .unwrap()
+            .write_all(&vec![0u8; free - 64 * 1024])
+            .unwrap();
+
+        let result = copy_file(&mut fs, &host.join("sub/big.bin"), "big.bin");
+        assert!(matches!(result, Err(FsError::NoSpace)));
+        assert!(matches!(fs.lookup("big.bin"), Err(FsError::NotFound(_))));
+
+        fs.create_dir("tree").unwrap();
+        let result = copy_children(&mut fs, &host, "tree", &mut Vec::new(), &mut |_, _| Ok(()));
+        assert!(matches!(result, Err(FsError::NoSpace)));
+        assert!(matches!(fs.lookup("tree/sub/big.bin"), Err(FsError::NotFound(_))));
+        remove_tree(&mut fs, "tree").unwrap();
+        assert!(matches!(fs.lookup("tree"), Err(FsError::NotFound(_))));
+
+        std::fs::remove_dir_all(&host).unwrap();
+        fs.flush().unwrap();
+        let report = fsck::check(fs.fat32_mut()).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
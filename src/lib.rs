@@ -0,0 +1,13 @@
+pub mod block_device;
+pub mod build_image;
+pub mod error;
+pub mod fat32;
+pub mod fs;
+pub mod fsck;
+pub mod host_copy;
+pub mod mkfs;
+pub mod timestamp;
+
+pub use block_device::{BlockDevice, OffsetDevice};
+pub use error::FsError;
+pub use fs::{FatFile, Fs};
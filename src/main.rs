@@ -1,4 +1,3 @@
-mod fat32;
 mod shell;
 mod commands;
 mod open_files;
@@ -6,18 +5,56 @@ mod open_files;
 use std::env;
 use std::fs::File;
 use std::io::{self};
+use std::path::Path;
 
-use fat32::FAT32;
+use filesys::block_device::{BlockDevice, OffsetDevice};
+use filesys::error::FsError;
+use filesys::fs::Fs;
+use filesys::build_image;
+use filesys::mkfs::{self, MkfsOptions};
+
+use crate::shell::BatchOptions;
 
 fn main() -> io::Result<()> {
     // Parse command-line arguments
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: filesys [FAT32_IMAGE]");
-        std::process::exit(1);
+    match args.get(1).map(String::as_str) {
+        Some("mkfs") => return make_image(&args[2..]),
+        Some("build-image") => return build_image(&args[2..]),
+        _ => {}
     }
+    let options = match parse_args(&args[1..]) {
+        Some(options) => options,
+        None => {
+            eprintln!("Usage: filesys [--offset BYTES] [-c COMMANDS | --script FILE] [--echo] [--stop-on-error] [FAT32_IMAGE]");
+            eprintln!("       filesys mkfs [FAT32_IMAGE] [SIZE] [--label LABEL] [--serial HEX] [--cluster-size BYTES] [--force]");
+            eprintln!("       filesys build-image [SRC_DIR] [FAT32_IMAGE] [--size SIZE] [--label LABEL] [--force]");
+            std::process::exit(1);
+        }
+    };
+    let image_path = &options.image_path;
 
-    let image_path = &args[1];
+    // Commands given on the command line or in a script run without a prompt
+    let batch = match (&options.commands, &options.script) {
+        (Some(commands), None) => Some(shell::split_commands(commands)),
+        (None, Some(script)) => match std::fs::read_to_string(script) {
+            Ok(text) => Some(text.lines().map(String::from).collect()),
+            Err(_) => {
+                eprintln!("Error: Cannot read script file '{}'.", script);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let batch = batch.map(|commands| {
+        (
+            commands,
+            BatchOptions {
+                echo: options.echo,
+                stop_on_error: options.stop_on_error,
+            },
+        )
+    });
 
     // Open the image file with read and write permissions
     let image_file = File::options().read(true).write(true).open(image_path);
@@ -25,18 +62,243 @@ fn main() -> io::Result<()> {
         eprintln!("Error: Cannot open image file '{}'.", image_path);
         std::process::exit(1);
     }
-    let mut image_file = image_file.unwrap();
+    let image_file = image_file.unwrap();
+
+    // Shown in the shell prompt
+    let image_name = Path::new(image_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| image_path.clone());
+    let image_name = image_name.as_str();
+
+    // A volume inside a partitioned disk image is mounted through a window
+    // starting at the partition offset
+    match &options.offset {
+        None => mount(image_file, image_name, batch),
+        Some(offset) => {
+            let offset: u64 = match offset.parse() {
+                Ok(offset) => offset,
+                Err(_) => {
+                    eprintln!("Error: Invalid offset '{}'.", offset);
+                    std::process::exit(1);
+                }
+            };
+            match OffsetDevice::new(image_file, offset, None) {
+                Ok(device) => mount(device, image_name, batch),
+                Err(_) => {
+                    eprintln!("Error: Offset {} is past the end of the image.", offset);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
 
+fn mount<D: BlockDevice>(
+    device: D,
+    image_name: &str,
+    batch: Option<(Vec<String>, BatchOptions)>,
+) -> io::Result<()> {
     // Initialize FAT32 file system
-    let fat32 = FAT32::new(&mut image_file);
-    if fat32.is_err() {
-        eprintln!("Error: Invalid FAT32 file system.");
+    let mut fs = match Fs::new(device) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for warning in &fs.fat32().mount_warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    // Run the shell; scripts can tell from the exit status whether a
+    // command failed
+    let all_succeeded = match batch {
+        Some((commands, options)) => shell::run_batch(&mut fs, image_name, &commands, &options)?,
+        None => shell::run_shell(&mut fs, image_name)?,
+    };
+    if !all_succeeded {
         std::process::exit(1);
     }
-    let mut fat32 = fat32.unwrap();
 
-    // Run the shell
-    shell::run_shell(&mut image_file, &mut fat32)?;
+    Ok(())
+}
+
+// Options for mounting an image
+struct Args {
+    image_path: String,
+    offset: Option<String>,
+    commands: Option<String>,
+    script: Option<String>,
+    echo: bool,
+    stop_on_error: bool,
+}
+
+// Parses the arguments after the program name. Returns None when they
+// don't form a valid command line.
+fn parse_args(args: &[String]) -> Option<Args> {
+    let mut image_path = None;
+    let mut parsed = Args {
+        image_path: String::new(),
+        offset: None,
+        commands: None,
+        script: None,
+        echo: false,
+        stop_on_error: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--offset" => parsed.offset = Some(args.next()?.clone()),
+            "-c" => parsed.commands = Some(args.next()?.clone()),
+            "--script" => parsed.script = Some(args.next()?.clone()),
+            "--echo" => parsed.echo = true,
+            "--stop-on-error" => parsed.stop_on_error = true,
+            _ if arg.starts_with('-') || image_path.is_some() => return None,
+            _ => image_path = Some(arg.clone()),
+        }
+    }
+
+    if parsed.commands.is_some() && parsed.script.is_some() {
+        return None;
+    }
+    parsed.image_path = image_path?;
+    Some(parsed)
+}
 
+// `filesys mkfs IMAGE SIZE [options]`: creates a new, empty FAT32 image
+fn make_image(args: &[String]) -> io::Result<()> {
+    let usage = || -> ! {
+        eprintln!("Usage: filesys mkfs [FAT32_IMAGE] [SIZE] [--label LABEL] [--serial HEX] [--cluster-size BYTES] [--force]");
+        std::process::exit(1);
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let image_path = &args[0];
+    let size = match parse_size(&args[1]) {
+        Some(size) => size,
+        None => {
+            eprintln!("Error: Invalid size '{}'.", args[1]);
+            std::process::exit(1);
+        }
+    };
+
+    let mut options = MkfsOptions::default();
+    let mut rest = args[2..].iter();
+    while let Some(option) = rest.next() {
+        if option == "--force" {
+            options.overwrite = true;
+            continue;
+        }
+        let value = match rest.next() {
+            Some(value) => value,
+            None => usage(),
+        };
+        match option.as_str() {
+            "--label" => options.volume_label = Some(value.clone()),
+            "--serial" => match u32::from_str_radix(value.trim_start_matches("0x"), 16) {
+                Ok(serial) => options.volume_id = Some(serial),
+                Err(_) => {
+                    eprintln!("Error: Invalid serial number '{}'.", value);
+                    std::process::exit(1);
+                }
+            },
+            "--cluster-size" => match parse_size(value) {
+                Some(cluster_size) if cluster_size <= u32::MAX as u64 => {
+                    options.cluster_size = Some(cluster_size as u32)
+                }
+                _ => {
+                    eprintln!("Error: Invalid cluster size '{}'.", value);
+                    std::process::exit(1);
+                }
+            },
+            _ => usage(),
+        }
+    }
+
+    if let Err(e) = mkfs::create_image(image_path, size, &options) {
+        report_create_error(&e);
+    }
+    println!("Created FAT32 image '{}' ({} bytes).", image_path, size);
     Ok(())
 }
+
+// `filesys build-image SRC_DIR IMAGE [options]`: creates an image holding a
+// copy of a host directory tree
+fn build_image(args: &[String]) -> io::Result<()> {
+    let usage = || -> ! {
+        eprintln!("Usage: filesys build-image [SRC_DIR] [FAT32_IMAGE] [--size SIZE] [--label LABEL] [--force]");
+        std::process::exit(1);
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let (src_dir, image_path) = (Path::new(&args[0]), Path::new(&args[1]));
+
+    let mut size = None;
+    let mut options = MkfsOptions::default();
+    let mut rest = args[2..].iter();
+    while let Some(option) = rest.next() {
+        if option == "--force" {
+            options.overwrite = true;
+            continue;
+        }
+        let value = match rest.next() {
+            Some(value) => value,
+            None => usage(),
+        };
+        match option.as_str() {
+            "--size" => match parse_size(value) {
+                Some(value) => size = Some(value),
+                None => {
+                    eprintln!("Error: Invalid size '{}'.", value);
+                    std::process::exit(1);
+                }
+            },
+            "--label" => options.volume_label = Some(value.clone()),
+            _ => usage(),
+        }
+    }
+
+    let report = match build_image::build_image(src_dir, image_path, size, &options) {
+        Ok(report) => report,
+        Err(e) => report_create_error(&e),
+    };
+    for (path, short_name) in &report.shortened {
+        println!("Shortened '{}' to '{}'.", path, short_name);
+    }
+    for path in &report.skipped {
+        println!("Skipped '{}': not a regular file or directory.", path.display());
+    }
+    println!(
+        "Created FAT32 image '{}' with {} directories and {} files ({} bytes).",
+        image_path.display(),
+        report.directories,
+        report.files,
+        report.bytes
+    );
+    Ok(())
+}
+
+// Exits after an error from creating an image, pointing out --force when
+// the image already exists
+fn report_create_error(e: &FsError) -> ! {
+    match e {
+        FsError::AlreadyExists(_) => eprintln!("Error: {} Use --force to replace it.", e),
+        _ => eprintln!("Error: {}", e),
+    }
+    std::process::exit(1);
+}
+
+// A byte count with an optional K, M or G suffix (powers of 1024)
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, multiplier) = match text.chars().last()?.to_ascii_uppercase() {
+        'K' => (&text[..text.len() - 1], 1 << 10),
+        'M' => (&text[..text.len() - 1], 1 << 20),
+        'G' => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
@@ -0,0 +1,294 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, Result};
+use crate::fat32::{
+    volume_label_bytes, FSINFO_LEAD_SIG, FSINFO_STRUCT_SIG, FSINFO_TRAIL_SIG,
+};
+
+const BYTES_PER_SECTOR: u16 = 512;
+const RESERVED_SECTORS: u16 = 32;
+const NUM_FATS: u8 = 2;
+const FS_INFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const ROOT_CLUSTER: u32 = 2;
+
+// FAT32 needs at least this many clusters, or it would be read as FAT16
+pub const MIN_CLUSTERS: u32 = 65525;
+const MAX_CLUSTERS: u32 = 0x0FFFFFF5;
+
+#[derive(Default)]
+pub struct MkfsOptions {
+    // Up to 11 characters; stored in the boot sector and the root directory
+    pub volume_label: Option<String>,
+    // Volume serial number; derived from the current time when None
+    pub volume_id: Option<u32>,
+    // Cluster size in bytes; picked from the volume size when None
+    pub cluster_size: Option<u32>,
+    // Replace an existing file at the image path instead of failing
+    pub overwrite: bool,
+}
+
+// Creates the image file at `path` with `size` bytes and formats it. The
+// options are checked first, and a file that can't be formatted is removed.
+pub fn create_image<P: AsRef<Path>>(path: P, size: u64, options: &MkfsOptions) -> Result<()> {
+    let path = path.as_ref();
+    geometry(size, options)?;
+    let mut image_file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(options.overwrite)
+        .create_new(!options.overwrite)
+        .truncate(options.overwrite)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                FsError::AlreadyExists(path.display().to_string())
+            }
+            _ => FsError::Io(e),
+        })?;
+    let result = image_file
+        .set_len(size)
+        .map_err(FsError::from)
+        .and_then(|()| format(&mut image_file, options));
+    if result.is_err() {
+        drop(image_file);
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+// Writes an empty FAT32 file system over the whole device
+pub fn format<D: BlockDevice>(device: &mut D, options: &MkfsOptions) -> Result<()> {
+    let Geometry {
+        total_sectors,
+        sectors_per_cluster,
+        fat_size,
+        total_clusters,
+        label,
+    } = geometry(device.size()?, options)?;
+    let volume_id = options.volume_id.unwrap_or_else(default_volume_id);
+
+    let sector_size = BYTES_PER_SECTOR as usize;
+    let cluster_bytes = sectors_per_cluster as usize * sector_size;
+    let fat_start = RESERVED_SECTORS as u64;
+    let data_start = fat_start + NUM_FATS as u64 * fat_size as u64;
+
+    // Clear the reserved area, both FATs and the root directory cluster
+    let zeros = vec![0u8; 64 * 1024];
+    let clear_end = data_start * sector_size as u64 + cluster_bytes as u64;
+    let mut offset = 0;
+    while offset < clear_end {
+        let len = std::cmp::min(zeros.len() as u64, clear_end - offset) as usize;
+        device.write_at(offset, &zeros[..len])?;
+        offset += len as u64;
+    }
+
+    let boot_sector = build_boot_sector(
+        total_sectors,
+        sectors_per_cluster,
+        fat_size,
+        volume_id,
+        &label,
+    );
+    let fs_info = build_fs_info(total_clusters - 1, ROOT_CLUSTER + 1);
+    for first in [0, BACKUP_BOOT_SECTOR as u64] {
+        device.write_sector(first, &boot_sector)?;
+        device.write_sector(first + FS_INFO_SECTOR as u64, &fs_info)?;
+    }
+
+    // Entries 0 and 1 are reserved; the root directory is a one-cluster chain
+    let mut fat_start_entries = [0u8; 12];
+    fat_start_entries[0..4].copy_from_slice(&0x0FFFFFF8u32.to_le_bytes());
+    fat_start_entries[4..8].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    fat_start_entries[8..12].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    for index in 0..NUM_FATS as u64 {
+        let fat_offset = (fat_start + index * fat_size as u64) * sector_size as u64;
+        device.write_at(fat_offset, &fat_start_entries)?;
+    }
+
+    if options.volume_label.is_some() {
+        // The label is also kept as an entry in the root directory
+        let mut label_entry = [0u8; 32];
+        label_entry[0..11].copy_from_slice(&label);
+        label_entry[11] = 0x08;
+        device.write_at(data_start * sector_size as u64, &label_entry)?;
+    }
+
+    device.flush()?;
+    Ok(())
+}
+
+// Layout `format` picks for a device of a given size
+struct Geometry {
+    total_sectors: u32,
+    sectors_per_cluster: u8,
+    fat_size: u32,
+    total_clusters: u32,
+    label: [u8; 11],
+}
+
+// Works out the layout for a device of `size` bytes, failing if the size or
+// options can't make a FAT32 volume
+fn geometry(size: u64, options: &MkfsOptions) -> Result<Geometry> {
+    let total_sectors = size / BYTES_PER_SECTOR as u64;
+    if total_sectors > u32::MAX as u64 {
+        return Err(FsError::InvalidArgument(
+            "Image is too large for FAT32.".to_string(),
+        ));
+    }
+    let total_sectors = total_sectors as u32;
+
+    let sectors_per_cluster = sectors_per_cluster(total_sectors, options)?;
+    let label = volume_label_bytes(options.volume_label.as_deref())?;
+
+    let fat_size = fat_size(total_sectors, sectors_per_cluster);
+    let data_sectors =
+        total_sectors.saturating_sub(RESERVED_SECTORS as u32 + NUM_FATS as u32 * fat_size);
+    let total_clusters = data_sectors / sectors_per_cluster as u32;
+    if total_clusters < MIN_CLUSTERS {
+        return Err(FsError::InvalidArgument(format!(
+            "Image is too small for FAT32 with {}-byte clusters ({} clusters, at least {} needed).",
+            sectors_per_cluster as u32 * BYTES_PER_SECTOR as u32,
+            total_clusters,
+            MIN_CLUSTERS
+        )));
+    }
+    if total_clusters > MAX_CLUSTERS {
+        return Err(FsError::InvalidArgument(
+            "Too many clusters for FAT32; use a larger cluster size.".to_string(),
+        ));
+    }
+    Ok(Geometry {
+        total_sectors,
+        sectors_per_cluster,
+        fat_size,
+        total_clusters,
+        label,
+    })
+}
+
+// Cluster size in bytes `format` uses for a device of `size` bytes
+pub fn cluster_size(size: u64, options: &MkfsOptions) -> Result<u32> {
+    let total_sectors = std::cmp::min(size / BYTES_PER_SECTOR as u64, u32::MAX as u64) as u32;
+    Ok(sectors_per_cluster(total_sectors, options)? as u32 * BYTES_PER_SECTOR as u32)
+}
+
+fn sectors_per_cluster(total_sectors: u32, options: &MkfsOptions) -> Result<u8> {
+    match options.cluster_size {
+        Some(size) => sectors_per_cluster_for(size),
+        None => default_sectors_per_cluster(total_sectors),
+    }
+}
+
+// Sectors per cluster from Microsoft's table for FAT32 volumes with 512-byte
+// sectors
+fn default_sectors_per_cluster(total_sectors: u32) -> Result<u8> {
+    match total_sectors {
+        0..=66600 => Err(FsError::InvalidArgument(
+            "Image is too small for FAT32 (at least 33 MiB needed).".to_string(),
+        )),
+        66601..=532480 => Ok(1),
+        532481..=16777216 => Ok(8),
+        16777217..=33554432 => Ok(16),
+        33554433..=67108864 => Ok(32),
+        _ => Ok(64),
+    }
+}
+
+fn sectors_per_cluster_for(cluster_size: u32) -> Result<u8> {
+    let sectors = cluster_size / BYTES_PER_SECTOR as u32;
+    if !cluster_size.is_multiple_of(BYTES_PER_SECTOR as u32)
+        || !sectors.is_power_of_two()
+        || sectors > 128
+    {
+        return Err(FsError::InvalidArgument(format!(
+            "Invalid cluster size {}; it must be a power of two from 512 to 65536 bytes.",
+            cluster_size
+        )));
+    }
+    Ok(sectors as u8)
+}
+
+// Sectors per FAT, using the calculation from Microsoft's FAT specification
+fn fat_size(total_sectors: u32, sectors_per_cluster: u8) -> u32 {
+    let tmp1 = total_sectors.saturating_sub(RESERVED_SECTORS as u32) as u64;
+    let tmp2 = ((256 * sectors_per_cluster as u64) + NUM_FATS as u64) / 2;
+    tmp1.div_ceil(tmp2) as u32
+}
+
+fn default_volume_id() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() as u32) ^ now.subsec_nanos()
+}
+
+fn build_boot_sector(
+    total_sectors: u32,
+    sectors_per_cluster: u8,
+    fat_size: u32,
+    volume_id: u32,
+    label: &[u8; 11],
+) -> [u8; 512] {
+    let mut sector = [0u8; 512];
+    sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]); // jump over the BPB
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+    sector[11..13].copy_from_slice(&BYTES_PER_SECTOR.to_le_bytes());
+    sector[13] = sectors_per_cluster;
+    sector[14..16].copy_from_slice(&RESERVED_SECTORS.to_le_bytes());
+    sector[16] = NUM_FATS;
+    // Root entry count, 16-bit total sectors and 16-bit FAT size stay 0
+    sector[21] = 0xF8; // media: fixed disk
+    sector[24..26].copy_from_slice(&63u16.to_le_bytes()); // sectors per track
+    sector[26..28].copy_from_slice(&255u16.to_le_bytes()); // number of heads
+    sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    sector[36..40].copy_from_slice(&fat_size.to_le_bytes());
+    // Extended flags 0: the FAT is mirrored to every copy
+    sector[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    sector[48..50].copy_from_slice(&FS_INFO_SECTOR.to_le_bytes());
+    sector[50..52].copy_from_slice(&BACKUP_BOOT_SECTOR.to_le_bytes());
+    sector[64] = 0x80; // drive number
+    sector[66] = 0x29; // extended boot signature
+    sector[67..71].copy_from_slice(&volume_id.to_le_bytes());
+    sector[71..82].copy_from_slice(label);
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+}
+
+fn build_fs_info(free_count: u32, next_free: u32) -> [u8; 512] {
+    let mut sector = [0u8; 512];
+    sector[0..4].copy_from_slice(&FSINFO_LEAD_SIG.to_le_bytes());
+    sector[484..488].copy_from_slice(&FSINFO_STRUCT_SIG.to_le_bytes());
+    sector[488..492].copy_from_slice(&free_count.to_le_bytes());
+    sector[492..496].copy_from_slice(&next_free.to_le_bytes());
+    sector[508..512].copy_from_slice(&FSINFO_TRAIL_SIG.to_le_bytes());
+    sector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_create_leaves_no_file() {
+        let path = std::env::temp_dir().join(format!("filesys-mkfs-{}.img", std::process::id()));
+        let result = create_image(&path, 10 * 1024 * 1024, &MkfsOptions::default());
+        assert!(matches!(result, Err(FsError::InvalidArgument(_))));
+        assert!(!path.exists());
+
+        let options = MkfsOptions {
+            volume_label: Some("TWELVE CHARS".to_string()),
+            ..MkfsOptions::default()
+        };
+        assert!(create_image(&path, 40 * 1024 * 1024, &options).is_err());
+        assert!(!path.exists());
+
+        create_image(&path, 40 * 1024 * 1024, &MkfsOptions::default()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 40 * 1024 * 1024);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,8 @@
 use std::collections::HashMap;
 
+use filesys::error::{FsError, Result};
+use filesys::fat32::DirectoryEntry;
+
 #[derive(Clone, PartialEq)]
 pub enum FileMode {
     ReadOnly,
@@ -11,12 +14,14 @@ pub struct OpenFile {
     pub filename: String,
     pub mode: FileMode,
     pub offset: u32,
-    pub first_cluster: u32,
-    pub file_size: u32,
+    // Directory entry of the file, kept in sync with writes
+    pub entry: DirectoryEntry,
 }
 
+// Open files are keyed by the image offset of their directory entry, so
+// different paths to the same file find the same open file
 pub struct OpenFiles {
-    files: HashMap<String, OpenFile>,
+    files: HashMap<u64, OpenFile>,
 }
 
 impl OpenFiles {
@@ -26,33 +31,30 @@ impl OpenFiles {
         }
     }
 
-    pub fn open_file(&mut self, file: OpenFile) -> Result<(), String> {
+    pub fn open_file(&mut self, file: OpenFile) -> Result<()> {
         if self.files.len() >= 10 {
-            return Err("Error: Maximum number of open files reached.".to_string());
+            return Err(FsError::TooManyOpenFiles);
         }
-        if self.files.contains_key(&file.filename) {
-            return Err(format!("Error: File '{}' is already open.", file.filename));
+        if self.files.contains_key(&file.entry.offset) {
+            return Err(FsError::FileBusy(file.filename));
         }
-        self.files.insert(file.filename.clone(), file);
+        self.files.insert(file.entry.offset, file);
         Ok(())
     }
 
-    pub fn close_file(&mut self, filename: &str) -> Result<(), String> {
-        if self.files.remove(filename).is_none() {
-            return Err(format!("Error: File '{}' is not open.", filename));
-        }
-        Ok(())
+    pub fn close_file(&mut self, entry_offset: u64) -> Option<OpenFile> {
+        self.files.remove(&entry_offset)
     }
 
-    pub fn get_file_mut(&mut self, filename: &str) -> Option<&mut OpenFile> {
-        self.files.get_mut(filename)
+    pub fn get_file_mut(&mut self, entry_offset: u64) -> Option<&mut OpenFile> {
+        self.files.get_mut(&entry_offset)
     }
 
     pub fn list_open_files(&self) -> Vec<&OpenFile> {
         self.files.values().collect()
     }
 
-    pub fn is_file_open(&self, filename: &str) -> bool {
-        self.files.contains_key(filename)
+    pub fn is_file_open(&self, entry_offset: u64) -> bool {
+        self.files.contains_key(&entry_offset)
     }
 }
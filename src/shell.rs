@@ -1,118 +1,257 @@
-use std::fs::File;
 use std::io::{self, Write};
 
-use crate::commands;
-use crate::fat32::FAT32;
+use filesys::block_device::BlockDevice;
+use filesys::error::FsError;
+use filesys::fs::Fs;
+
+use crate::commands::{self, LsOptions, LsSort};
 use crate::open_files::OpenFiles;
 
-pub fn run_shell(image_file: &mut File, fat32: &mut FAT32) -> io::Result<()> {
+// Options for running a list of commands instead of reading them from the
+// terminal
+pub struct BatchOptions {
+    // Print each command, after a prompt, before running it
+    pub echo: bool,
+    // Skip the remaining commands once one fails
+    pub stop_on_error: bool,
+}
+
+// Runs commands until `exit` or end of input. Returns whether every
+// command succeeded.
+pub fn run_shell<D: BlockDevice>(fs: &mut Fs<D>, image_name: &str) -> io::Result<bool> {
     let mut input = String::new();
     let stdin = io::stdin();
-
-    let mut current_dir_cluster = fat32.boot_sector.root_cluster;
-    let mut open_files = OpenFiles::new();
+    let mut session = Session::new();
 
     loop {
-        print!("filesys> ");
+        print!("{}> ", prompt(fs, image_name));
         io::stdout().flush()?;
         input.clear();
-        stdin.read_line(&mut input)?;
+        if stdin.read_line(&mut input)? == 0 {
+            // End of input, treat like exit
+            println!();
+            break;
+        }
+        if !session.execute(fs, &input) {
+            break;
+        }
+    }
+
+    Ok(session.all_succeeded)
+}
+
+// Runs `commands` without prompting. Returns whether every command that
+// was run succeeded.
+pub fn run_batch<D: BlockDevice>(
+    fs: &mut Fs<D>,
+    image_name: &str,
+    commands: &[String],
+    options: &BatchOptions,
+) -> io::Result<bool> {
+    let mut session = Session::new();
+
+    for command in commands {
+        if options.echo && !command.trim().is_empty() {
+            println!("{}> {}", prompt(fs, image_name), command.trim());
+        }
+        if !session.execute(fs, command) {
+            break;
+        }
+        if options.stop_on_error && !session.all_succeeded {
+            break;
+        }
+    }
+
+    Ok(session.all_succeeded)
+}
+
+// Splits the argument of `-c` into commands at semicolons and newlines
+// that are not inside double quotes
+pub fn split_commands(text: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            ';' | '\n' if !in_quotes => commands.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    commands.push(current);
+
+    commands.retain(|command| !command.trim().is_empty());
+    commands
+}
+
+// State kept between commands
+struct Session {
+    open_files: OpenFiles,
+    all_succeeded: bool,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            open_files: OpenFiles::new(),
+            all_succeeded: true,
+        }
+    }
+
+    // Runs one command line and reports any error. Returns false once the
+    // shell should exit.
+    fn execute<D: BlockDevice>(&mut self, fs: &mut Fs<D>, input: &str) -> bool {
         let input = input.trim();
 
-        if input.is_empty() {
-            continue;
+        // Blank lines and comments in scripts are skipped
+        if input.is_empty() || input.starts_with('#') {
+            return true;
         }
 
         let mut args = input.split_whitespace();
         let command = args.next().unwrap();
 
-        match command {
+        let result = match command {
             "exit" => {
                 println!("Exiting...");
-                break;
+                return false;
+            }
+            "info" => match args.next() {
+                None => commands::info(fs, false),
+                Some("--json") => commands::info(fs, true),
+                Some(flag) => Err(usage(&format!("Invalid option '{}'.", flag))),
+            },
+            "fatcheck" => match args.next() {
+                None => commands::fatcheck(fs, false),
+                Some("--repair") => commands::fatcheck(fs, true),
+                Some(flag) => Err(usage(&format!("Invalid option '{}'.", flag))),
+            },
+            "fsck" => {
+                let (mut repair, mut dry_run, mut salvage) = (false, false, false);
+                let mut result = Ok(());
+                for flag in args {
+                    match flag {
+                        "--repair" => repair = true,
+                        "--dry-run" => dry_run = true,
+                        "--salvage" => salvage = true,
+                        _ => result = Err(usage(&format!("Invalid option '{}'.", flag))),
+                    }
+                }
+                result.and_then(|()| commands::fsck(fs, repair, dry_run, salvage))
             }
-            "info" => {
-                commands::info(fat32)?;
+            "bootsector" => match (args.next(), args.next()) {
+                (action, None) => commands::bootsector(fs, action),
+                _ => Err(usage("'bootsector' command takes one action.")),
+            },
+            "pwd" => commands::pwd(fs),
+            "label" => {
+                // Labels may contain spaces
+                let new_label = args.collect::<Vec<&str>>().join(" ");
+                if new_label.is_empty() {
+                    commands::label(fs, None)
+                } else {
+                    commands::label(fs, Some(&new_label))
+                }
             }
             "ls" => {
-                commands::ls(image_file, fat32, current_dir_cluster)?;
+                let mut options = LsOptions::default();
+                let mut dirnames = Vec::new();
+                let mut result = Ok(());
+                for arg in args {
+                    let flags = match arg.strip_prefix('-') {
+                        Some(flags) if !flags.is_empty() => flags,
+                        _ => {
+                            dirnames.push(arg);
+                            continue;
+                        }
+                    };
+                    for flag in flags.chars() {
+                        match flag {
+                            'l' => options.long = true,
+                            'a' => options.all = true,
+                            'S' => options.sort = LsSort::Size,
+                            't' => options.sort = LsSort::Time,
+                            'r' => options.reverse = true,
+                            _ => result = Err(usage(&format!("Invalid option '-{}'.", flag))),
+                        }
+                    }
+                }
+                match dirnames[..] {
+                    _ if result.is_err() => result,
+                    [] => commands::ls(fs, ".", &options),
+                    [dirname] => commands::ls(fs, dirname, &options),
+                    _ => Err(usage("'ls' command takes one directory name.")),
+                }
             }
             "cd" => {
                 if let Some(dirname) = args.next() {
-                    commands::cd(image_file, fat32, &mut current_dir_cluster, dirname)?;
+                    commands::cd(fs, dirname)
                 } else {
-                    eprintln!("Error: 'cd' command requires a directory name.");
+                    Err(usage("'cd' command requires a directory name."))
                 }
             }
             "mkdir" => {
                 if let Some(dirname) = args.next() {
-                    commands::mkdir(image_file, fat32, current_dir_cluster, dirname)?;
+                    commands::mkdir(fs, dirname)
                 } else {
-                    eprintln!("Error: 'mkdir' command requires a directory name.");
+                    Err(usage("'mkdir' command requires a directory name."))
                 }
             }
             "creat" => {
                 if let Some(filename) = args.next() {
-                    commands::creat(image_file, fat32, current_dir_cluster, filename)?;
+                    commands::creat(fs, filename)
                 } else {
-                    eprintln!("Error: 'creat' command requires a file name.");
+                    Err(usage("'creat' command requires a file name."))
                 }
             }
             "open" => {
-                let filename = args.next();
-                let flags = args.next();
-                if filename.is_some() && flags.is_some() {
+                if let (Some(filename), Some(flags)) = (args.next(), args.next()) {
                     commands::open(
-                        image_file,
-                        fat32,
-                        current_dir_cluster,
-                        filename.unwrap(),
-                        flags.unwrap(),
-                        &mut open_files,
-                    )?;
+                        fs,
+                        filename,
+                        flags,
+                        &mut self.open_files,
+                    )
                 } else {
-                    eprintln!("Error: 'open' command requires a filename and flags.");
+                    Err(usage("'open' command requires a filename and flags."))
                 }
             }
             "close" => {
                 if let Some(filename) = args.next() {
-                    commands::close(filename, &mut open_files)?;
+                    commands::close(fs, filename, &mut self.open_files)
                 } else {
-                    eprintln!("Error: 'close' command requires a filename.");
+                    Err(usage("'close' command requires a filename."))
                 }
             }
-            "lsof" => {
-                commands::lsof(&open_files)?;
-            }
+            "lsof" => commands::lsof(&self.open_files),
             "size" => {
                 if let Some(filename) = args.next() {
-                    commands::size(image_file, fat32, current_dir_cluster, filename)?;
+                    commands::size(fs, filename)
                 } else {
-                    eprintln!("Error: 'size' command requires a filename.");
+                    Err(usage("'size' command requires a filename."))
                 }
             }
             "lseek" => {
-                let filename = args.next();
-                let offset = args.next();
-                if filename.is_some() && offset.is_some() {
-                    commands::lseek(filename.unwrap(), offset.unwrap(), &mut open_files)?;
+                if let (Some(filename), Some(offset)) = (args.next(), args.next()) {
+                    commands::lseek(fs, filename, offset, &mut self.open_files)
                 } else {
-                    eprintln!("Error: 'lseek' command requires a filename and offset.");
+                    Err(usage("'lseek' command requires a filename and offset."))
                 }
             }
             "read" => {
-                let filename = args.next();
-                let size = args.next();
-                if filename.is_some() && size.is_some() {
+                if let (Some(filename), Some(size)) = (args.next(), args.next()) {
                     commands::read(
-                        image_file,
-                        fat32,
-                        filename.unwrap(),
-                        size.unwrap(),
-                        &mut open_files,
-                    )?;
+                        fs,
+                        filename,
+                        size,
+                        &mut self.open_files,
+                    )
                 } else {
-                    eprintln!("Error: 'read' command requires a filename and size.");
+                    Err(usage("'read' command requires a filename and size."))
                 }
             }
             "write" => {
@@ -121,67 +260,106 @@ pub fn run_shell(image_file: &mut File, fat32: &mut FAT32) -> io::Result<()> {
                     let string = args.collect::<Vec<&str>>().join(" ");
                     if !string.is_empty() {
                         commands::write(
-                            image_file,
-                            fat32,
-                            current_dir_cluster,
+                            fs,
                             filename,
                             &string,
-                            &mut open_files,
-                        )?;
+                            &mut self.open_files,
+                        )
                     } else {
-                        eprintln!("Error: 'write' command requires a string to write.");
+                        Err(usage("'write' command requires a string to write."))
                     }
                 } else {
-                    eprintln!("Error: 'write' command requires a filename and string.");
+                    Err(usage("'write' command requires a filename and string."))
                 }
             }
             "rm" => {
                 if let Some(filename) = args.next() {
                     commands::rm(
-                        image_file,
-                        fat32,
-                        current_dir_cluster,
+                        fs,
                         filename,
-                        &open_files,
-                    )?;
+                        &self.open_files,
+                    )
                 } else {
-                    eprintln!("Error: 'rm' command requires a filename.");
+                    Err(usage("'rm' command requires a filename."))
                 }
             }
             "rmdir" => {
                 if let Some(dirname) = args.next() {
-                    commands::rmdir(
-                        image_file,
-                        fat32,
-                        current_dir_cluster,
-                        dirname,
-                        &open_files,
-                    )?;
+                    commands::rmdir(fs, dirname)
                 } else {
-                    eprintln!("Error: 'rmdir' command requires a directory name.");
+                    Err(usage("'rmdir' command requires a directory name."))
                 }
             }
             "rename" => {
-                let old_name = args.next();
-                let new_name = args.next();
-                if old_name.is_some() && new_name.is_some() {
+                if let (Some(old_name), Some(new_name)) = (args.next(), args.next()) {
                     commands::rename(
-                        image_file,
-                        fat32,
-                        current_dir_cluster,
-                        old_name.unwrap(),
-                        new_name.unwrap(),
-                        &open_files,
-                    )?;
+                        fs,
+                        old_name,
+                        new_name,
+                        &self.open_files,
+                    )
                 } else {
-                    eprintln!("Error: 'rename' command requires old and new filenames.");
+                    Err(usage("'rename' command requires old and new filenames."))
                 }
             }
-            _ => {
-                eprintln!("Unknown command: {}", command);
+            "attrib" => {
+                let (changes, filenames): (Vec<&str>, Vec<&str>) =
+                    args.partition(|arg| arg.starts_with('+') || arg.starts_with('-'));
+                match filenames[..] {
+                    [filename] => commands::attrib(fs, filename, &changes),
+                    _ => Err(usage("'attrib' command requires a file name.")),
+                }
+            }
+            "put" => {
+                let (recursive, paths) = copy_args(args);
+                match paths[..] {
+                    [host_path] => commands::put(fs, host_path, None, recursive),
+                    [host_path, image_path] => {
+                        commands::put(fs, host_path, Some(image_path), recursive)
+                    }
+                    _ => Err(usage("'put' command requires a host path.")),
+                }
+            }
+            "get" => {
+                let (recursive, paths) = copy_args(args);
+                match paths[..] {
+                    [image_path] => commands::get(fs, image_path, None, recursive),
+                    [image_path, host_path] => {
+                        commands::get(fs, image_path, Some(host_path), recursive)
+                    }
+                    _ => Err(usage("'get' command requires a file name.")),
+                }
             }
+            _ => Err(usage(&format!("Unknown command: {}", command))),
+        };
+        // Write FAT changes back after every command, so the image stays
+        // consistent if the shell is killed
+        let result = result.and_then(|()| fs.flush());
+
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            self.all_succeeded = false;
         }
+        true
+    }
+}
+
+// Image name followed by the current directory, e.g. `fat32.img/A/B/`
+fn prompt<D: BlockDevice>(fs: &Fs<D>, image_name: &str) -> String {
+    let path = fs.current_path();
+    if path == "/" {
+        format!("{}/", image_name)
+    } else {
+        format!("{}{}/", image_name, path)
     }
+}
+
+// Splits the arguments of `put` and `get` into the `-r` flag and the paths
+fn copy_args<'a>(args: impl Iterator<Item = &'a str>) -> (bool, Vec<&'a str>) {
+    let (flags, paths): (Vec<&str>, Vec<&str>) = args.partition(|arg| *arg == "-r");
+    (!flags.is_empty(), paths)
+}
 
-    Ok(())
+fn usage(message: &str) -> FsError {
+    FsError::InvalidArgument(message.to_string())
 }
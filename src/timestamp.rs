@@ -0,0 +1,124 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// A date and time as stored in directory entries: two-second resolution,
+// years 1980 to 2107. FAT has no time zone; times are kept in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+const MIN: Timestamp = Timestamp {
+    year: 1980,
+    month: 1,
+    day: 1,
+    hour: 0,
+    minute: 0,
+    second: 0,
+};
+
+const MAX: Timestamp = Timestamp {
+    year: 2107,
+    month: 12,
+    day: 31,
+    hour: 23,
+    minute: 59,
+    second: 58,
+};
+
+impl Timestamp {
+    // The current time, or SOURCE_DATE_EPOCH when it is set so that
+    // generated images and test output are reproducible
+    pub fn now() -> Self {
+        let epoch = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|secs| secs.parse().ok());
+        match epoch {
+            Some(secs) => Self::from_system_time(UNIX_EPOCH + Duration::from_secs(secs)),
+            None => Self::from_system_time(SystemTime::now()),
+        }
+    }
+
+    // Times outside the range FAT can store are clamped to it
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => return MIN,
+        };
+        let (year, month, day) = civil_from_days(secs / 86400);
+        if year < MIN.year as u64 {
+            return MIN;
+        }
+        if year > MAX.year as u64 {
+            return MAX;
+        }
+        let secs_of_day = secs % 86400;
+        Timestamp {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    // Decodes an entry's date and time fields. Returns None for a zero
+    // date, which means the time was never set.
+    pub fn from_fat(date: u16, time: u16) -> Option<Self> {
+        if date == 0 {
+            return None;
+        }
+        Some(Timestamp {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        })
+    }
+
+    pub fn fat_date(&self) -> u16 {
+        ((self.year - 1980) << 9) | ((self.month as u16) << 5) | self.day as u16
+    }
+
+    pub fn fat_time(&self) -> u16 {
+        ((self.hour as u16) << 11) | ((self.minute as u16) << 5) | (self.second as u16 / 2)
+    }
+
+    // Creation times also keep the odd second, in units of 10 ms
+    pub fn fat_time_tenths(&self) -> u8 {
+        (self.second % 2) * 100
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Year, month and day of the day `days` after 1970-01-01, using Howard
+// Hinnant's civil_from_days algorithm
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,182 @@
+// Runs every command script in tests/ against a freshly formatted image and
+// compares the shell's output, and the tree left on the image, with the
+// matching `.expected` file. Run with `BLESS=1 cargo test` to rewrite the
+// expected files after an intended change in output.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use filesys::block_device::BlockDevice;
+use filesys::fs::Fs;
+use filesys::fsck;
+use filesys::mkfs::{self, MkfsOptions};
+
+const IMAGE_SIZE: u64 = 40 * 1024 * 1024;
+
+#[test]
+fn golden_scripts() {
+    let tests_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let bless = std::env::var_os("BLESS").is_some();
+
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&tests_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts found in {}", tests_dir.display());
+
+    let mut failed = Vec::new();
+    for script in &scripts {
+        let actual = run_script(script);
+        let expected_path = script.with_extension("expected");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if actual != expected {
+            eprintln!(
+                "--- {} differs from {}:\n{}",
+                script.display(),
+                expected_path.display(),
+                actual
+            );
+            failed.push(script.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+
+    assert!(
+        failed.is_empty(),
+        "output changed for {:?}; rerun with BLESS=1 if this is intended",
+        failed
+    );
+}
+
+// Formats a new image, runs `script` on it and returns everything the
+// expected file records
+fn run_script(script: &Path) -> String {
+    // The image name shows up in the prompt, so only the directory is
+    // made unique
+    let dir = std::env::temp_dir().join(format!("filesys-golden-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let image = dir
+        .join(script.file_stem().unwrap())
+        .with_extension("img");
+
+    // A fixed serial number keeps `info` output the same between runs
+    let options = MkfsOptions {
+        volume_id: Some(0x12345678),
+        // An interrupted run may have left the image behind
+        overwrite: true,
+        ..MkfsOptions::default()
+    };
+    mkfs::create_image(&image, IMAGE_SIZE, &options).unwrap();
+    // Scripts about damaged images get the damage before they run
+    let stem = script.file_stem().unwrap().to_string_lossy();
+    if stem.starts_with("test_fsck") {
+        seed_damage(&image);
+    } else if stem == "test_bootsector_damaged" {
+        // An impossible sector size in the boot sector but not its backup
+        write_at(&image, 11, &513u16.to_le_bytes());
+    }
+
+    // stdout and stderr share one pipe, so each error shows up right after
+    // the command that caused it
+    let (mut reader, writer) = std::io::pipe().unwrap();
+    // Scripts name host files relative to the repository root
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filesys"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        // Entries get this time instead of the current one
+        .env("SOURCE_DATE_EPOCH", "1700000000")
+        .arg(&image)
+        .arg("--script")
+        .arg(script)
+        .arg("--echo")
+        .stdout(writer.try_clone().unwrap())
+        .stderr(writer)
+        .spawn()
+        .unwrap();
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).unwrap();
+    let status = child.wait().unwrap();
+
+    let mut result = String::new();
+    result.push_str("--- output\n");
+    result.push_str(&String::from_utf8_lossy(&output));
+    writeln!(result, "--- exit status\n{}", status.code().unwrap_or(-1)).unwrap();
+    result.push_str("--- tree\n");
+
+    let mut fs = Fs::open_path(&image).unwrap();
+    dump_tree(&mut fs, "", &mut result);
+    let report = fsck::check(fs.fat32_mut()).unwrap();
+    if report.is_clean() {
+        result.push_str("--- fsck\nclean\n");
+    } else {
+        result.push_str("--- fsck\n");
+        for problem in &report.problems {
+            writeln!(result, "{}", problem).unwrap();
+        }
+    }
+
+    drop(fs);
+    fs::remove_file(&image).unwrap();
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+// Leaves the problems fsck looks for on the image: two files sharing a
+// cluster, a chain no entry points to and FAT copies that differ
+fn seed_damage(image: &Path) {
+    let mut fs = Fs::open_path(image).unwrap();
+    fs.create("a.txt").unwrap().write_all(&[b'a'; 1500]).unwrap();
+    fs.create("b.txt").unwrap().write_all(&[b'b'; 1000]).unwrap();
+    let a = fs.lookup("a.txt").unwrap().first_cluster;
+    let b = fs.lookup("b.txt").unwrap().first_cluster;
+
+    let fat32 = fs.fat32_mut();
+    let a_chain = fat32.get_cluster_chain(a).unwrap();
+    // b.txt's second cluster becomes a.txt's last one; its own is lost
+    fat32.set_next_cluster(b, a_chain[a_chain.len() - 1]).unwrap();
+
+    // A two-cluster chain holding data but no directory entry
+    let first = fat32.allocate_cluster().unwrap();
+    let second = fat32.allocate_cluster().unwrap();
+    fat32.set_next_cluster(first, second).unwrap();
+    fat32.write_cluster_data(first, 0, b"lost data").unwrap();
+    fs.flush().unwrap();
+
+    // Mark a free cluster as used in the second FAT only
+    let offset = fs.fat32().fat_copy_offset(1) + 100 * 4;
+    drop(fs);
+    write_at(image, offset, &0x0FFFFFFFu32.to_le_bytes());
+}
+
+fn write_at(image: &Path, offset: u64, data: &[u8]) {
+    let mut file = fs::File::options().write(true).open(image).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(data).unwrap();
+}
+
+// One line per entry below `path`, directories before their contents
+fn dump_tree<D: BlockDevice>(fs: &mut Fs<D>, path: &str, out: &mut String) {
+    let dir = if path.is_empty() { "/" } else { path };
+    for entry in fs.read_dir(dir).unwrap() {
+        let name = entry.display_name();
+        // Skip the entries every directory has
+        if name == "." || name == ".." {
+            continue;
+        }
+        let entry_path = format!("{}/{}", path, name);
+        if entry.is_directory() {
+            writeln!(out, "{}/", entry_path).unwrap();
+            dump_tree(fs, &entry_path, out);
+        } else {
+            writeln!(out, "{} ({} bytes)", entry_path, entry.file_size).unwrap();
+        }
+    }
+}